@@ -0,0 +1,415 @@
+//! Everything the client knows about its lobby, kept separate from the terminal so it can be
+//! tested.
+use protocol::{ClientId, ClientMessage, LobbyCode, PlayerState, ServerMessage};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How many chat lines stay on screen.
+const CHAT_LINES: usize = 5;
+
+#[derive(Default)]
+pub enum Phase {
+    /// Connected, waiting to hear which lobby we're in.
+    #[default]
+    Connecting,
+    Waiting,
+    Countdown(Instant),
+    Racing(Instant),
+    /// The race was won by the given player, others may still be typing.
+    Finished {
+        started: Instant,
+        winner: ClientId,
+    },
+}
+
+#[derive(Default)]
+pub struct Game {
+    pub client_id: Option<ClientId>,
+    pub lobby: Option<LobbyCode>,
+    /// The race being run or replayed, for REPLAY.
+    pub race: Option<u64>,
+    pub is_leader: bool,
+    pub words: Vec<String>,
+    pub players: BTreeMap<ClientId, PlayerState>,
+    pub names: HashMap<ClientId, String>,
+    /// The server doesn't echo our own NAME back.
+    pub own_name: Option<String>,
+    pub latencies: HashMap<ClientId, u64>,
+    /// Our own progress, updated as we type rather than when the server echoes it.
+    pub state: PlayerState,
+    /// Wrong keys pressed since the last right one, which backspace takes back.
+    pub typos: u32,
+    pub phase: Phase,
+    pub chat: VecDeque<String>,
+    /// The last thing the server told us that doesn't fit anywhere else.
+    pub notice: Option<String>,
+    pub shutdown: Option<Instant>,
+}
+
+impl Game {
+    /// Updates the game from a server message, returning the reply if it needs one.
+    pub fn apply(&mut self, message: ServerMessage) -> Option<ClientMessage> {
+        match message {
+            ServerMessage::Connected(id) => self.client_id = Some(id),
+            ServerMessage::Created(code) => {
+                self.lobby = Some(code);
+                self.is_leader = true;
+                self.phase = Phase::Waiting;
+            }
+            ServerMessage::Joined(code) => {
+                self.lobby = Some(code);
+                self.phase = Phase::Waiting;
+            }
+            ServerMessage::Invalid { command, state } => {
+                self.notice = Some(format!("Can't {} while the lobby is {}", command, state))
+            }
+            ServerMessage::CreateFailed => {
+                self.notice = Some("The server couldn't find a free lobby code".to_owned())
+            }
+            ServerMessage::JoinFailed { banned: true } => {
+                self.notice = Some("You're banned from that lobby".to_owned())
+            }
+            ServerMessage::JoinFailed { banned: false } => {
+                self.notice = Some("Couldn't find a lobby to join".to_owned())
+            }
+            ServerMessage::Words(words) => self.words = words,
+            ServerMessage::State { client_id, state } => {
+                self.players.insert(client_id, state);
+            }
+            ServerMessage::Countdown(seconds) => {
+                self.phase = Phase::Countdown(Instant::now() + Duration::from_secs(seconds));
+            }
+            ServerMessage::Starting => {
+                self.state = PlayerState::default();
+                self.typos = 0;
+                self.phase = Phase::Racing(Instant::now());
+            }
+            ServerMessage::Finished(winner) => {
+                // The winning STATE is announced as FINISHED instead of being echoed.
+                self.players.insert(
+                    winner,
+                    PlayerState {
+                        current_word: self.words.len(),
+                        ..PlayerState::default()
+                    },
+                );
+                if let Phase::Racing(started) = self.phase {
+                    self.phase = Phase::Finished { started, winner };
+                }
+            }
+            ServerMessage::NewLeader(id) => self.is_leader = self.client_id == Some(id),
+            ServerMessage::PlayerDisconnected(id) | ServerMessage::PlayerKicked(id) => {
+                self.players.remove(&id);
+                self.latencies.remove(&id);
+            }
+            ServerMessage::Ping(nonce) => return Some(ClientMessage::Pong(nonce)),
+            ServerMessage::Latency { client_id, millis } => {
+                self.latencies.insert(client_id, millis);
+            }
+            ServerMessage::ServerShutdown(seconds) => {
+                self.shutdown = Some(Instant::now() + Duration::from_secs(seconds));
+            }
+            ServerMessage::Kicked(reason) => self.notice = Some(format!("Kicked: {}", reason)),
+            ServerMessage::LobbyClosed => self.notice = Some("The lobby was closed".to_owned()),
+            ServerMessage::LobbyExpiring(seconds) => {
+                self.notice = Some(format!(
+                    "Nothing's happening, the lobby closes in {}s",
+                    seconds
+                ))
+            }
+            ServerMessage::Announcement(message) => self.notice = Some(message),
+            ServerMessage::Banned => {
+                self.notice = Some("You're banned from this server".to_owned())
+            }
+            ServerMessage::Name { client_id, name } => {
+                self.names.insert(client_id, name);
+            }
+            ServerMessage::Chat { name, message, .. } => {
+                self.chat.push_back(format!("<{}> {}", name, message));
+                if self.chat.len() > CHAT_LINES {
+                    self.chat.pop_front();
+                }
+            }
+            ServerMessage::Throttled {
+                class,
+                violations,
+                max_violations,
+            } => {
+                self.notice = Some(format!(
+                    "Slow down! Too many {} commands (warning {} of {})",
+                    class, violations, max_violations
+                ))
+            }
+            ServerMessage::ProtocolError(message) => {
+                self.notice = Some(format!("Protocol error: {}", message))
+            }
+            ServerMessage::NoGhost => {
+                self.notice = Some("No recorded run on this text to race yet".to_owned())
+            }
+            ServerMessage::Accuracy { client_id, words } => {
+                let text_length = self.words.iter().map(|word| word.len() + 1).sum::<usize>();
+                let mistakes: u32 = words
+                    .iter()
+                    .map(|word| word.uncorrected + word.corrected)
+                    .sum();
+                let accuracy =
+                    100.0 * text_length as f64 / (text_length as f64 + f64::from(mistakes));
+                let mut notice = format!("{}: {:.1}% accurate", self.name(client_id), accuracy);
+                if let Some(worst) = words
+                    .iter()
+                    .max_by_key(|word| (word.uncorrected + word.corrected, word.backspaces))
+                    .and_then(|worst| self.words.get(worst.word))
+                {
+                    notice += &format!(", worst on {:?}", worst);
+                }
+                self.notice = Some(notice);
+            }
+            ServerMessage::Race(id) => self.race = Some(id),
+            ServerMessage::Daily(date) => {
+                self.notice = Some(format!(
+                    "Daily challenge for {}, only your first race today counts",
+                    date
+                ))
+            }
+            ServerMessage::DailyBoard { date, finishes } if finishes.is_empty() => {
+                self.notice = Some(format!("No one has finished the {} daily yet", date))
+            }
+            ServerMessage::DailyBoard { date, finishes } => {
+                let places: Vec<_> = finishes
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (name, millis))| {
+                        format!("{}. {} {:.1}s", i + 1, name, *millis as f64 / 1000.0)
+                    })
+                    .collect();
+                self.notice = Some(format!("Daily {}: {}", date, places.join(", ")))
+            }
+            ServerMessage::Stats(stats) if stats.races == 0 => {
+                self.notice = Some("Finish a race to see your stats".to_owned())
+            }
+            ServerMessage::Stats(stats) => {
+                // Just the worst few, to fit on the notice line.
+                let mut parts = vec![format!("{} races", stats.races)];
+                let bigrams: Vec<_> = stats
+                    .bigrams
+                    .iter()
+                    .take(3)
+                    .map(|(bigram, millis)| format!("{} {}ms", bigram, millis))
+                    .collect();
+                if !bigrams.is_empty() {
+                    parts.push(format!("slowest pairs {}", bigrams.join(" ")));
+                }
+                let characters: Vec<_> = stats
+                    .characters
+                    .iter()
+                    .take(3)
+                    .map(|(character, missed, typed)| format!("{} {}/{}", character, missed, typed))
+                    .collect();
+                if !characters.is_empty() {
+                    parts.push(format!("most missed {}", characters.join(" ")));
+                }
+                let words: Vec<_> = stats
+                    .words
+                    .iter()
+                    .take(3)
+                    .map(|(word, wpm)| format!("{} {}wpm", word, wpm))
+                    .collect();
+                if !words.is_empty() {
+                    parts.push(format!("slowest words {}", words.join(" ")));
+                }
+                self.notice = Some(parts.join(", "))
+            }
+            ServerMessage::ReplayEnd(id) => {
+                self.notice = Some(format!("End of the replay of race {}", id))
+            }
+            ServerMessage::NoReplay(id) => {
+                self.notice = Some(format!("There's no recording of race {}", id))
+            }
+        }
+        None
+    }
+
+    /// Checks a typed character against the text, returning the STATE to send if anything
+    /// changed.
+    pub fn type_char(&mut self, c: char) -> Option<ClientMessage> {
+        if !matches!(self.phase, Phase::Racing(_) | Phase::Finished { .. }) {
+            return None;
+        }
+        let word = self.words.get(self.state.current_word)?;
+        // Words advance as soon as they're complete, so the space between them is optional.
+        if c == ' ' && self.state.current_completed_character == 0 {
+            return None;
+        }
+        let mistakes = &mut self.state.mistakes;
+        if word[self.state.current_completed_character..].starts_with(c) {
+            mistakes.uncorrected += self.typos;
+            mistakes.position = None;
+            self.typos = 0;
+            self.state.current_completed_character += c.len_utf8();
+            if self.state.current_completed_character >= word.len() {
+                self.state.current_word += 1;
+                self.state.current_completed_character = 0;
+            }
+            self.state.did_make_mistake = false;
+        } else {
+            mistakes.position = Some(self.state.current_completed_character);
+            self.typos += 1;
+            self.state.did_make_mistake = true;
+        }
+        Some(ClientMessage::State(self.state))
+    }
+
+    /// Takes back the last wrong key, if there was one, returning the STATE to send.
+    pub fn backspace(&mut self) -> Option<ClientMessage> {
+        if !matches!(self.phase, Phase::Racing(_) | Phase::Finished { .. })
+            || self.state.current_word >= self.words.len()
+        {
+            return None;
+        }
+        let mistakes = &mut self.state.mistakes;
+        mistakes.backspaces += 1;
+        if self.typos > 0 {
+            self.typos -= 1;
+            mistakes.corrected += 1;
+            if self.typos == 0 {
+                mistakes.position = None;
+                self.state.did_make_mistake = false;
+            }
+        }
+        Some(ClientMessage::State(self.state))
+    }
+
+    pub fn name(&self, id: ClientId) -> String {
+        let name = if Some(id) == self.client_id {
+            self.own_name.as_ref()
+        } else {
+            self.names.get(&id)
+        };
+        name.cloned().unwrap_or_else(|| format!("P{}", id))
+    }
+
+    /// A player's progress, using our own typing rather than the server's echo for us.
+    pub fn progress(&self, id: ClientId) -> PlayerState {
+        if Some(id) == self.client_id {
+            self.state
+        } else {
+            self.players.get(&id).copied().unwrap_or_default()
+        }
+    }
+
+    /// Words per minute since the race started, counting 5 characters as a word.
+    pub fn wpm(&self, id: ClientId) -> f64 {
+        let started = match self.phase {
+            Phase::Racing(started) | Phase::Finished { started, .. } => started,
+            _ => return 0.0,
+        };
+        let state = self.progress(id);
+        let typed: usize = self
+            .words
+            .iter()
+            .take(state.current_word)
+            // One extra for the space.
+            .map(|word| word.len() + 1)
+            .sum::<usize>()
+            + state.current_completed_character;
+        let minutes = started.elapsed().as_secs_f64() / 60.0;
+        if minutes > 0.0 {
+            typed as f64 / 5.0 / minutes
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn racing(words: &[&str]) -> Game {
+        let mut game = Game::default();
+        game.apply(ServerMessage::Connected(1));
+        game.apply(ServerMessage::Created(LobbyCode::new(b"ABCDE").unwrap()));
+        game.apply(ServerMessage::Words(
+            words.iter().map(|&word| word.to_owned()).collect(),
+        ));
+        game.apply(ServerMessage::Starting);
+        game
+    }
+
+    fn state(word: usize, character: usize, mistake: bool) -> Option<ClientMessage> {
+        Some(ClientMessage::State(PlayerState {
+            current_word: word,
+            current_completed_character: character,
+            did_make_mistake: mistake,
+            ..PlayerState::default()
+        }))
+    }
+
+    fn mistakes(game: &Game) -> (Option<usize>, u32, u32, u32) {
+        let mistakes = game.state.mistakes;
+        (
+            mistakes.position,
+            mistakes.uncorrected,
+            mistakes.corrected,
+            mistakes.backspaces,
+        )
+    }
+
+    #[test]
+    fn ignores_typing_before_the_race() {
+        let mut game = racing(&["cow"]);
+        game.phase = Phase::Waiting;
+        assert_eq!(game.type_char('c'), None);
+    }
+
+    #[test]
+    fn advances_through_words() {
+        let mut game = racing(&["to", "be"]);
+        assert_eq!(game.type_char('t'), state(0, 1, false));
+        assert_eq!(game.type_char('o'), state(1, 0, false));
+        assert_eq!(game.type_char(' '), None);
+        assert_eq!(game.type_char('b'), state(1, 1, false));
+        assert_eq!(game.type_char('e'), state(2, 0, false));
+        // Nothing left to type.
+        assert_eq!(game.type_char('x'), None);
+    }
+
+    #[test]
+    fn mistakes_hold_position_until_corrected() {
+        let mut game = racing(&["cow"]);
+        game.type_char('x');
+        assert_eq!(mistakes(&game), (Some(0), 0, 0, 0));
+        assert_eq!(game.type_char(' '), None);
+        game.type_char('c');
+        assert_eq!(mistakes(&game), (None, 1, 0, 0));
+        game.type_char('C');
+        assert_eq!(mistakes(&game), (Some(1), 1, 0, 0));
+    }
+
+    #[test]
+    fn backspace_corrects_mistakes() {
+        let mut game = racing(&["cow"]);
+        game.type_char('x');
+        game.type_char('y');
+        game.backspace();
+        assert!(game.state.did_make_mistake);
+        assert_eq!(mistakes(&game), (Some(0), 0, 1, 1));
+        game.backspace();
+        assert!(!game.state.did_make_mistake);
+        assert_eq!(mistakes(&game), (None, 0, 2, 2));
+        // Nothing left to take back.
+        game.backspace();
+        assert_eq!(mistakes(&game), (None, 0, 2, 3));
+        game.type_char('c');
+        assert_eq!(game.state.current_completed_character, 1);
+    }
+
+    #[test]
+    fn answers_pings() {
+        let mut game = Game::default();
+        assert_eq!(
+            game.apply(ServerMessage::Ping(7)),
+            Some(ClientMessage::Pong(7))
+        );
+    }
+}
@@ -0,0 +1,185 @@
+//! A terminal client, for racing without Neovim.
+mod game;
+mod ui;
+
+use anyhow::{bail, ensure, Context, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, execute, terminal};
+use game::{Game, Phase};
+use protocol::{ClientMessage, LobbyCode, ServerMessage};
+use std::io::{self, prelude::*, BufReader};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// How often the board is redrawn while nothing happens, for the countdown and WPM.
+const TICK: Duration = Duration::from_millis(100);
+
+#[derive(StructOpt)]
+#[structopt(name = "typeracer")]
+struct Opt {
+    #[structopt(long, default_value = "kiani.io")]
+    host: String,
+    #[structopt(long, default_value = "1234")]
+    port: u16,
+    /// What the other players see you as.
+    #[structopt(long)]
+    name: Option<String>,
+    #[structopt(subcommand)]
+    cmd: Subcommand,
+}
+
+#[derive(StructOpt)]
+enum Subcommand {
+    /// Create a new lobby and wait for others to join.
+    Create,
+    /// Join a lobby by its code.
+    Join { code: String },
+    /// Join any lobby that hasn't started racing yet.
+    JoinRandom,
+    /// Race today's daily challenge in a new lobby.
+    Daily,
+    /// Watch a recorded race.
+    Replay {
+        race: u64,
+        /// How many times faster than it happened to play it back.
+        #[structopt(long)]
+        speed: Option<u32>,
+    },
+}
+
+/// Reads server messages on a separate thread so the UI never blocks on the network. The
+/// channel closes when the server hangs up.
+fn spawn_reader(stream: TcpStream) -> Receiver<Result<ServerMessage>> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stream).split(b'\n') {
+            let message = line
+                .map_err(anyhow::Error::from)
+                .and_then(|line| ServerMessage::decode(&line));
+            let failed = message.is_err();
+            if sender.send(message).is_err() || failed {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Puts the terminal back the way we found it, even if we bail out early.
+struct RawTerminal;
+
+impl RawTerminal {
+    fn enter() -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)?;
+        Ok(RawTerminal)
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn send(stream: &mut TcpStream, message: ClientMessage) -> Result<()> {
+    stream
+        .write_all(message.encode().as_bytes())
+        .context("Lost connection to the server")
+}
+
+fn run(
+    mut stream: TcpStream,
+    messages: Receiver<Result<ServerMessage>>,
+    name: Option<String>,
+) -> Result<()> {
+    let _terminal = RawTerminal::enter()?;
+    let mut stdout = io::stdout();
+    let mut game = Game {
+        own_name: name,
+        ..Game::default()
+    };
+    let mut connected = true;
+    loop {
+        while connected {
+            match messages.try_recv() {
+                Ok(message) => {
+                    let message = message.context("Bad message from the server")?;
+                    if let ServerMessage::CreateFailed
+                    | ServerMessage::JoinFailed { .. }
+                    | ServerMessage::NoReplay(_) = message
+                    {
+                        // There's nothing to show, so don't leave a blank board up.
+                        game.apply(message);
+                        bail!("{}", game.notice.take().unwrap_or_default());
+                    }
+                    if let Some(reply) = game.apply(message) {
+                        send(&mut stream, reply)?;
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    connected = false;
+                    game.notice = Some("Disconnected from the server".to_owned());
+                }
+            }
+        }
+        ui::draw(&mut stdout, &game)?;
+
+        if !event::poll(TICK)? {
+            continue;
+        }
+        let key = match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => key,
+            _ => continue,
+        };
+        let message = match key.code {
+            KeyCode::Esc => break,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+            KeyCode::Enter if game.is_leader && matches!(game.phase, Phase::Waiting) => {
+                Some(ClientMessage::Start)
+            }
+            KeyCode::F(2) => Some(ClientMessage::AddGhost { record: false }),
+            KeyCode::F(3) => Some(ClientMessage::AddGhost { record: true }),
+            KeyCode::F(4) => Some(ClientMessage::DailyBoard),
+            KeyCode::F(5) => Some(ClientMessage::Stats),
+            KeyCode::Char(c) => game.type_char(c),
+            KeyCode::Backspace => game.backspace(),
+            _ => None,
+        };
+        if let (Some(message), true) = (message, connected) {
+            send(&mut stream, message)?;
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
+    let first = match &opt.cmd {
+        Subcommand::Create => ClientMessage::Create,
+        Subcommand::Join { code } => {
+            ClientMessage::Join(LobbyCode::new(code.as_bytes()).context("Invalid lobby code")?)
+        }
+        Subcommand::JoinRandom => ClientMessage::JoinRandom,
+        Subcommand::Daily => ClientMessage::Daily,
+        Subcommand::Replay { race, speed } => {
+            ensure!(*speed != Some(0), "The replay speed has to be at least 1");
+            ClientMessage::Replay {
+                race: *race,
+                speed: *speed,
+            }
+        }
+    };
+    let mut stream = TcpStream::connect((opt.host.as_str(), opt.port))
+        .with_context(|| format!("Failed to connect to {}:{}", opt.host, opt.port))?;
+    let messages = spawn_reader(stream.try_clone()?);
+    if let Some(name) = &opt.name {
+        send(&mut stream, ClientMessage::Name(name.clone()))?;
+    }
+    send(&mut stream, first)?;
+    run(stream, messages, opt.name)
+}
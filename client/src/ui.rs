@@ -0,0 +1,135 @@
+//! Draws the lobby board, laid out like the Neovim plugin's buffer.
+use crate::game::{Game, Phase};
+use crossterm::cursor::{MoveTo, MoveToNextLine};
+use crossterm::style::{Attribute, Color, Print, PrintStyledContent, Stylize};
+use crossterm::terminal::{self, Clear, ClearType};
+use crossterm::{queue, QueueableCommand};
+use protocol::ClientId;
+use std::io::{self, prelude::*};
+use std::time::Instant;
+
+fn seconds_until(deadline: Instant) -> u64 {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    // Round up so the countdown shows 1 rather than 0 in its last second.
+    (remaining.as_millis() as u64).div_ceil(1000)
+}
+
+fn status(game: &Game) -> String {
+    let line = match game.phase {
+        Phase::Connecting => "Connecting...".to_owned(),
+        Phase::Waiting if game.is_leader => "WAITING ON YOU! Press Enter to start.".to_owned(),
+        Phase::Waiting => "WAITING ON LEADER".to_owned(),
+        Phase::Countdown(deadline) => format!("COUNTDOWN {}", seconds_until(deadline)),
+        Phase::Racing(_) => "GO!!".to_owned(),
+        Phase::Finished { winner, .. } => format!("DONE! WINNER: {}", game.name(winner)),
+    };
+    match game.shutdown {
+        Some(deadline) => format!(
+            "{} (SERVER SHUTTING DOWN IN {}s)",
+            line,
+            seconds_until(deadline)
+        ),
+        None => line,
+    }
+}
+
+/// One row of the board: `*P1 [1]:  42 WPM   12ms chic|ken cow farm`, with the next character
+/// to type highlighted, in red if the player's last key was a mistake.
+fn draw_player(out: &mut impl Write, game: &Game, id: ClientId, width: usize) -> io::Result<()> {
+    let state = game.progress(id);
+    let latency = match game.latencies.get(&id) {
+        Some(millis) => format!("{:4}ms ", millis),
+        None => "       ".to_owned(),
+    };
+    let prefix = format!(
+        "{}{} [{}]: {:3} WPM {}",
+        if Some(id) == game.client_id { "*" } else { " " },
+        game.name(id),
+        state.current_word + 1,
+        game.wpm(id) as u64,
+        latency
+    );
+    let (typed, next, rest) = match game.words.get(state.current_word) {
+        Some(word) => {
+            let typed = &word[..state.current_completed_character.min(word.len())];
+            let mut remaining = word[typed.len()..].chars();
+            let next = remaining.next();
+            let mut rest = remaining.as_str().to_owned();
+            for word in &game.words[state.current_word + 1..] {
+                rest.push(' ');
+                rest.push_str(word);
+            }
+            (typed, next, rest)
+        }
+        None => ("", None, String::new()),
+    };
+    let next = next.map(String::from).unwrap_or_default();
+    let mut left = width;
+    for (text, highlight) in [
+        (prefix.as_str(), false),
+        (typed, false),
+        ("|", false),
+        (next.as_str(), true),
+        (rest.as_str(), false),
+    ] {
+        let text: String = text.chars().take(left).collect();
+        left -= text.chars().count();
+        if !highlight {
+            out.queue(Print(text))?;
+        } else if state.did_make_mistake {
+            out.queue(PrintStyledContent(text.with(Color::White).on(Color::Red)))?;
+        } else {
+            out.queue(PrintStyledContent(
+                text.attribute(Attribute::Bold)
+                    .attribute(Attribute::Underlined),
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn draw(out: &mut impl Write, game: &Game) -> io::Result<()> {
+    let (columns, _) = terminal::size()?;
+    let width = columns as usize;
+    let rule: String = "-".repeat(width.min(80));
+    queue!(out, MoveTo(0, 0), Clear(ClearType::All))?;
+    let code = match game.lobby {
+        Some(code) => code.to_string(),
+        None => "-".to_owned(),
+    };
+    let code = match game.race {
+        Some(race) => format!("LOBBY CODE: {}  RACE: {}", code, race),
+        None => format!("LOBBY CODE: {}", code),
+    };
+    let mut header = vec![code, status(game)];
+    if let Some(notice) = &game.notice {
+        header.push(notice.clone());
+    }
+    header.push(rule.clone());
+    for line in header {
+        queue!(out, Print(line), MoveToNextLine(1))?;
+    }
+    let mut ids: Vec<_> = game.players.keys().copied().collect();
+    if let Some(id) = game.client_id {
+        if game.lobby.is_some() && !ids.contains(&id) {
+            ids.push(id);
+            ids.sort_unstable();
+        }
+    }
+    for id in ids {
+        draw_player(out, game, id, width)?;
+        out.queue(MoveToNextLine(1))?;
+    }
+    queue!(out, Print(rule), MoveToNextLine(1))?;
+    for line in &game.chat {
+        queue!(out, Print(line), MoveToNextLine(1))?;
+    }
+    queue!(
+        out,
+        MoveToNextLine(1),
+        Print(
+            "F2 races your best run, F3 the record, F4 shows the daily board, F5 your stats, Esc or Ctrl-C quits"
+        )
+    )?;
+    out.flush()
+}
@@ -34,17 +34,25 @@ local function make_client(host, port, callback)
     word = 1,
     char = 1,
     err = false,
+    -- Wrong keys since the last right one, and the ones typed over before that.
+    typos = 0,
+    uncorrected = 0,
   }
   local stream = uv.new_tcp()
   local is_counting_down = nil
+  local server_shutdown = nil
 
   local buffer
   local start_time
   local finished
+  local race_id
 
   local client_id
 
   local players = {}
+  local latencies = {}
+  local names = {}
+  local chat_lines = {}
   local R = {}
 
   local function calculate_total_keys_typed(player)
@@ -63,7 +71,9 @@ local function make_client(host, port, callback)
     end
 
     local function send_state()
-      send(("STATE %d %d %d"):format(state.word - 1, state.char - 1, state.err and 1 or 0))
+      -- There's no backspace here, so nothing is ever corrected.
+      local position = state.err and tostring(state.char - 1) or "-"
+      send(("STATE %d %d %d %s %d 0 0"):format(state.word - 1, state.char - 1, state.err and 1 or 0, position, state.uncorrected))
     end
 
     R.create = function(callback)
@@ -84,6 +94,56 @@ local function make_client(host, port, callback)
       -- TODO(ashkan): use callback to warn connect?
     end
 
+    -- Starts a lobby on the daily text, or switches ours over to it.
+    R.daily = function()
+      send("DAILY")
+    end
+
+    R.daily_board = function()
+      send("DAILY_BOARD")
+    end
+
+    R.stats = function()
+      send("STATS")
+    end
+
+    -- Back to waiting for START once the race has a winner, leaders only.
+    R.restart = function()
+      send("RESTART")
+    end
+
+    R.kick = function(id)
+      send(("KICK %d"):format(id))
+    end
+
+    R.ban = function(id)
+      send(("BAN %d"):format(id))
+    end
+
+    R.chat = function(message)
+      send("CHAT "..message)
+    end
+
+    R.name = function(name)
+      send("NAME "..name)
+    end
+
+    R.promote = function(id)
+      send(("PROMOTE %d"):format(id))
+    end
+
+    R.add_bot = function(wpm, variance)
+      if variance then
+        send(("ADD_BOT %d %d"):format(wpm, variance))
+      else
+        send(("ADD_BOT %d"):format(wpm))
+      end
+    end
+
+    R.add_ghost = function(record)
+      send(record and "ADD_GHOST RECORD" or "ADD_GHOST")
+    end
+
     local command_handler = {}
 
     local function redraw()
@@ -118,7 +178,9 @@ local function make_client(host, port, callback)
             wpm = (total_keys_typed/5.0)/((uv.hrtime() - start_time)/1e9/60.0)
           end
         end
-        local prefix = format("%sP%d [%d]: %3d WPM ", is_me and "*" or " ", k, player.word, math.floor(wpm))
+        local latency = latencies[k] and format("%4dms ", latencies[k]) or "       "
+        local name = names[k] or format("P%d", k)
+        local prefix = format("%s%s [%d]: %3d WPM %s", is_me and "*" or " ", name, player.word, math.floor(wpm), latency)
         insert(player_lines, prefix..word_state)
         player_draw_data[k] = { prefix_len = #prefix }
       end
@@ -141,11 +203,16 @@ local function make_client(host, port, callback)
           state_line = "WAITING ON LEADER"
         end
       end
+      if server_shutdown then
+        state_line = format("%s (SERVER SHUTTING DOWN IN %ds)", state_line, server_shutdown)
+      end
       local lines = {
-        "LOBBY CODE: "..tostring(current_lobby),
+        "LOBBY CODE: "..tostring(current_lobby)..(race_id and ("  RACE: "..race_id) or ""),
         state_line,
         string.rep("-", 80),
         player_lines,
+        string.rep("-", 80),
+        chat_lines,
       }
       api.nvim_buf_set_lines(buffer, 0, -1, false, vim.tbl_flatten(lines))
       api.nvim_buf_clear_namespace(buffer, ns, 0, -1)
@@ -187,6 +254,8 @@ local function make_client(host, port, callback)
       -- TODO(ashkan): cleanup
       local target = assert(words[state.word]):sub(state.char,state.char)
       if target == key then
+        state.uncorrected = state.uncorrected + state.typos
+        state.typos = 0
         state.err = false
         state.char = state.char + 1
         if state.char > #words[state.word] then
@@ -194,6 +263,7 @@ local function make_client(host, port, callback)
           state.char = 1
         end
       else
+        state.typos = state.typos + 1
         state.err = true
       end
       send_state()
@@ -231,6 +301,38 @@ local function make_client(host, port, callback)
 
     function command_handler.PLAYER_DISCONNECTED(args)
       players[tonumber(args[1] or -1) or false] = nil
+      latencies[tonumber(args[1] or -1) or false] = nil
+    end
+
+    function command_handler.PLAYER_KICKED(args)
+      command_handler.PLAYER_DISCONNECTED(args)
+    end
+
+    function command_handler.NAME(args)
+      names[tonumber(args[1])] = args[2]
+    end
+
+    function command_handler.CHAT(args)
+      local id = table.remove(args, 1)
+      local name = table.remove(args, 1)
+      insert(chat_lines, format("<%s> %s", name or id, concat(args, " ")))
+      -- Only keep the last few lines on screen.
+      if #chat_lines > 5 then
+        table.remove(chat_lines, 1)
+      end
+    end
+
+    function command_handler.PING(args)
+      send("PONG "..args[1])
+    end
+
+    function command_handler.LATENCY(args)
+      latencies[tonumber(args[1])] = tonumber(args[2])
+    end
+
+    function command_handler.CREATE_FAILED(args)
+      assert(not current_lobby)
+      error("Failed to create a lobby!")
     end
 
     function command_handler.JOIN_FAILED(args)
@@ -257,9 +359,106 @@ local function make_client(host, port, callback)
     end
 
     function command_handler.NEW_LEADER(args)
-      if client_id == tonumber(args[1]) then
-        is_leader = true
+      is_leader = client_id == tonumber(args[1])
+      if is_leader then
         R.start = function() send "START" end
+      else
+        R.start = nil
+      end
+    end
+
+    function command_handler.SERVER_SHUTDOWN(args)
+      server_shutdown = tonumber(args[1])
+    end
+
+    function command_handler.ANNOUNCEMENT(args)
+      print("typeracer: "..concat(args, " "))
+    end
+
+    function command_handler.KICKED(args)
+      print("typeracer: kicked: "..concat(args, " "))
+    end
+
+    function command_handler.LOBBY_CLOSED(args)
+      print("typeracer: the lobby was closed")
+    end
+
+    function command_handler.LOBBY_EXPIRING(args)
+      print(format("typeracer: nothing's happening, the lobby closes in %ss", args[1]))
+    end
+
+    function command_handler.THROTTLED(args)
+      print(format("typeracer: slow down! too many %s commands (warning %s of %s)", args[1], args[2], args[3]))
+    end
+
+    function command_handler.INVALID(args)
+      print(format("typeracer: can't %s while the lobby is %s", args[1], args[2]))
+    end
+
+    function command_handler.PROTOCOL_ERROR(args)
+      print("typeracer: protocol error: "..concat(args, " "))
+    end
+
+    function command_handler.BANNED(args)
+      print("typeracer: you are banned from this server")
+    end
+
+    function command_handler.RACE(args)
+      race_id = args[1]
+    end
+
+    function command_handler.DAILY(args)
+      print("typeracer: daily challenge for "..args[1]..", only your first race today counts")
+    end
+
+    function command_handler.DAILY_BOARD(args)
+      local places = {}
+      for i = 2, #args, 2 do
+        insert(places, format("%d. %s %.1fs", #places + 1, args[i], tonumber(args[i+1]) / 1000))
+      end
+      if #places == 0 then
+        insert(places, "no one has finished yet")
+      end
+      print("typeracer: daily "..args[1]..": "..concat(places, ", "))
+    end
+
+    -- STATS <races> followed by "bigram <pair> <ms>", "char <c> <missed> <typed>" and
+    -- "word <word> <wpm>" entries, worst first.
+    function command_handler.STATS(args)
+      local lines = {}
+      local i = 2
+      while i <= #args do
+        local kind = args[i]
+        if kind == "bigram" then
+          insert(lines, format("%s %sms", args[i+1], args[i+2]))
+          i = i + 3
+        elseif kind == "char" then
+          insert(lines, format("%s missed %s of %s", args[i+1], args[i+2], args[i+3]))
+          i = i + 4
+        else
+          insert(lines, format("%s %s WPM", args[i+1], args[i+2]))
+          i = i + 3
+        end
+      end
+      print(format("typeracer: %s races: %s", args[1], concat(lines, ", ")))
+    end
+
+    function command_handler.NO_GHOST(args)
+      print("typeracer: no recorded run on this text to race yet")
+    end
+
+    -- Which words someone got wrong, as groups of word, uncorrected, corrected and backspaces.
+    function command_handler.ACCURACY(args)
+      if tonumber(args[1]) ~= client_id then return end
+      local wrong = {}
+      for i = 2, #args, 4 do
+        local word = words[tonumber(args[i]) + 1]
+        insert(wrong, format("%s (%d)", word, tonumber(args[i+1]) + tonumber(args[i+2])))
+      end
+      if #wrong == 0 then
+        print("typeracer: no mistakes!")
+      else
+        print("typeracer: mistakes in "..concat(wrong, ", "))
       end
     end
 
@@ -333,10 +532,61 @@ function M.join_random(host, port)
   end)
 end
 
+function M.daily(host, port)
+  if M.client then
+    return M.client.daily()
+  end
+  local kiani_io_addr = uv.getaddrinfo("kiani.io")[1].addr
+  return make_client(host or kiani_io_addr, port or 1234, function(client)
+    M.client = client
+    client.daily()
+  end)
+end
+
+function M.daily_board()
+  assert(M.client, "no session active").daily_board()
+end
+
+function M.stats()
+  assert(M.client, "no session active").stats()
+end
+
 function M.start()
   assert(assert(M.client, "no session active").start, "you're not the leader. you can't start!")()
 end
 
+function M.restart()
+  assert(M.client, "no session active").restart()
+end
+
+function M.kick(id)
+  assert(M.client, "no session active").kick(id)
+end
+
+function M.ban(id)
+  assert(M.client, "no session active").ban(id)
+end
+
+function M.promote(id)
+  assert(M.client, "no session active").promote(id)
+end
+
+function M.add_bot(wpm, variance)
+  assert(M.client, "no session active").add_bot(wpm, variance)
+end
+
+function M.add_ghost(record)
+  assert(M.client, "no session active").add_ghost(record)
+end
+
+function M.chat(message)
+  assert(M.client, "no session active").chat(message)
+end
+
+function M.name(name)
+  assert(M.client, "no session active").name(name)
+end
+
 function M.check_key(key)
   assert(M.client).check_key(key)
 end
@@ -0,0 +1,16 @@
+//! Feeds arbitrary lines to both decoders. Neither may panic, and anything that decodes has to
+//! survive an encode/decode round trip unchanged.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use protocol::{ClientMessage, ServerMessage};
+
+fuzz_target!(|line: &[u8]| {
+    if let Ok(message) = ClientMessage::decode(line) {
+        let encoded = message.encode();
+        assert_eq!(ClientMessage::decode(encoded.as_bytes()).unwrap(), message);
+    }
+    if let Ok(message) = ServerMessage::decode(line) {
+        let encoded = message.encode();
+        assert_eq!(ServerMessage::decode(encoded.as_bytes()).unwrap(), message);
+    }
+});
@@ -0,0 +1,340 @@
+//! Messages sent from clients to the server.
+use crate::{strip_newline, ClientId, LobbyCode, PlayerState, MAX_CHAT_LENGTH, MAX_NAME_LENGTH};
+use anyhow::{anyhow, ensure, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientMessage {
+    Start,
+    Create,
+    State(PlayerState),
+    Join(LobbyCode),
+    JoinRandom,
+    Restart,
+    Words,
+    /// Answers the server's PING with the same nonce.
+    Pong(u64),
+    Kick(ClientId),
+    /// Kick a player and keep their address from joining the lobby again.
+    Ban(ClientId),
+    /// Hand leadership of the lobby to another player.
+    Promote(ClientId),
+    Chat(String),
+    Name(String),
+    /// Add a server driven player typing at `wpm`, give or take `variance`.
+    AddBot {
+        wpm: u32,
+        variance: Option<u32>,
+    },
+    /// Race a ghost of our best run on the lobby's text, or of the fastest anyone has.
+    AddGhost {
+        record: bool,
+    },
+    /// Race today's daily challenge, in a new lobby or by switching ours over to its text.
+    Daily,
+    DailyBoard,
+    /// What our finished races say about where our typing is slow or sloppy.
+    Stats,
+    /// Stream a recorded race back, `speed` times as fast as it happened.
+    Replay {
+        race: u64,
+        speed: Option<u32>,
+    },
+}
+
+impl ClientMessage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientMessage::Start => "START",
+            ClientMessage::Create => "CREATE",
+            ClientMessage::State(_) => "STATE",
+            ClientMessage::Join(_) => "JOIN",
+            ClientMessage::JoinRandom => "JOIN_RANDOM",
+            ClientMessage::Restart => "RESTART",
+            ClientMessage::Words => "WORDS",
+            ClientMessage::Pong(_) => "PONG",
+            ClientMessage::Kick(_) => "KICK",
+            ClientMessage::Ban(_) => "BAN",
+            ClientMessage::Promote(_) => "PROMOTE",
+            ClientMessage::Chat(_) => "CHAT",
+            ClientMessage::Name(_) => "NAME",
+            ClientMessage::AddBot { .. } => "ADD_BOT",
+            ClientMessage::AddGhost { .. } => "ADD_GHOST",
+            ClientMessage::Daily => "DAILY",
+            ClientMessage::DailyBoard => "DAILY_BOARD",
+            ClientMessage::Stats => "STATS",
+            ClientMessage::Replay { .. } => "REPLAY",
+        }
+    }
+
+    /// The full line to send, including the trailing newline.
+    pub fn encode(&self) -> String {
+        match self {
+            ClientMessage::Start => "START\n".to_owned(),
+            ClientMessage::Create => "CREATE\n".to_owned(),
+            ClientMessage::State(state) => format!("STATE {}\n", state.encode()),
+            ClientMessage::Join(code) => format!("JOIN {}\n", code),
+            ClientMessage::JoinRandom => "JOIN RANDOM\n".to_owned(),
+            ClientMessage::Restart => "RESTART\n".to_owned(),
+            ClientMessage::Words => "WORDS\n".to_owned(),
+            ClientMessage::Pong(nonce) => format!("PONG {}\n", nonce),
+            ClientMessage::Kick(id) => format!("KICK {}\n", id),
+            ClientMessage::Ban(id) => format!("BAN {}\n", id),
+            ClientMessage::Promote(id) => format!("PROMOTE {}\n", id),
+            ClientMessage::Chat(message) => format!("CHAT {}\n", message),
+            ClientMessage::Name(name) => format!("NAME {}\n", name),
+            ClientMessage::AddBot {
+                wpm,
+                variance: None,
+            } => format!("ADD_BOT {}\n", wpm),
+            ClientMessage::AddBot {
+                wpm,
+                variance: Some(variance),
+            } => format!("ADD_BOT {} {}\n", wpm, variance),
+            ClientMessage::AddGhost { record: false } => "ADD_GHOST\n".to_owned(),
+            ClientMessage::AddGhost { record: true } => "ADD_GHOST RECORD\n".to_owned(),
+            ClientMessage::Daily => "DAILY\n".to_owned(),
+            ClientMessage::DailyBoard => "DAILY_BOARD\n".to_owned(),
+            ClientMessage::Stats => "STATS\n".to_owned(),
+            ClientMessage::Replay { race, speed: None } => format!("REPLAY {}\n", race),
+            ClientMessage::Replay {
+                race,
+                speed: Some(speed),
+            } => format!("REPLAY {} {}\n", race, speed),
+        }
+    }
+
+    pub fn decode(buffer: &[u8]) -> Result<Self> {
+        let buffer = strip_newline(buffer)?;
+        if buffer == b"START" {
+            return Ok(ClientMessage::Start);
+        } else if buffer == b"CREATE" {
+            return Ok(ClientMessage::Create);
+        } else if buffer.starts_with(b"STATE ") {
+            // STATE 1 30 1
+            let buffer = &buffer[b"STATE ".len()..];
+            let mut it = buffer.split(|c| c.is_ascii_whitespace());
+            return Ok(ClientMessage::State(PlayerState::decode(&mut it)?));
+        } else if buffer == b"JOIN RANDOM" {
+            return Ok(ClientMessage::JoinRandom);
+        } else if buffer.starts_with(b"JOIN ") {
+            let buffer = &buffer[b"JOIN ".len()..];
+            return Ok(ClientMessage::Join(LobbyCode::new(buffer)?));
+        } else if buffer == b"RESTART" {
+            return Ok(ClientMessage::Restart);
+        } else if buffer == b"WORDS" {
+            return Ok(ClientMessage::Words);
+        } else if buffer == b"DAILY" {
+            return Ok(ClientMessage::Daily);
+        } else if buffer == b"DAILY_BOARD" {
+            return Ok(ClientMessage::DailyBoard);
+        } else if buffer == b"STATS" {
+            return Ok(ClientMessage::Stats);
+        } else if buffer == b"ADD_GHOST" {
+            return Ok(ClientMessage::AddGhost { record: false });
+        } else if buffer == b"ADD_GHOST RECORD" {
+            return Ok(ClientMessage::AddGhost { record: true });
+        } else if buffer.starts_with(b"KICK ") {
+            let buffer = &buffer[b"KICK ".len()..];
+            return Ok(ClientMessage::Kick(
+                std::str::from_utf8(buffer)?.trim().parse()?,
+            ));
+        } else if buffer.starts_with(b"BAN ") {
+            let buffer = &buffer[b"BAN ".len()..];
+            return Ok(ClientMessage::Ban(
+                std::str::from_utf8(buffer)?.trim().parse()?,
+            ));
+        } else if buffer.starts_with(b"PROMOTE ") {
+            let buffer = &buffer[b"PROMOTE ".len()..];
+            return Ok(ClientMessage::Promote(
+                std::str::from_utf8(buffer)?.trim().parse()?,
+            ));
+        } else if buffer.starts_with(b"CHAT ") {
+            let message = String::from_utf8_lossy(&buffer[b"CHAT ".len()..]);
+            let message = message.trim();
+            ensure!(!message.is_empty(), "Empty CHAT message");
+            let message = match message.char_indices().nth(MAX_CHAT_LENGTH) {
+                // Don't leave whitespace at the end, it'd be trimmed if we sent it again.
+                Some((end, _)) => message[..end].trim_end(),
+                None => message,
+            };
+            return Ok(ClientMessage::Chat(message.to_owned()));
+        } else if buffer.starts_with(b"NAME ") {
+            let name = std::str::from_utf8(&buffer[b"NAME ".len()..])?.trim();
+            ensure!(
+                !name.is_empty() && name.chars().count() <= MAX_NAME_LENGTH,
+                "Names must be 1 to {} characters long",
+                MAX_NAME_LENGTH
+            );
+            ensure!(
+                name.chars().all(|c| !c.is_whitespace() && !c.is_control()),
+                "Names can't contain whitespace or control characters"
+            );
+            return Ok(ClientMessage::Name(name.to_owned()));
+        } else if buffer.starts_with(b"PONG ") {
+            let buffer = &buffer[b"PONG ".len()..];
+            let nonce = std::str::from_utf8(buffer)?.trim().parse()?;
+            return Ok(ClientMessage::Pong(nonce));
+        } else if buffer.starts_with(b"ADD_BOT ") {
+            // ADD_BOT 80 10
+            let buffer = std::str::from_utf8(&buffer[b"ADD_BOT ".len()..])?;
+            let mut it = buffer.split_ascii_whitespace();
+            let wpm: u32 = it
+                .next()
+                .ok_or_else(|| anyhow!("ADD_BOT needs a speed"))?
+                .parse()?;
+            ensure!(wpm > 0, "Bots need to type at least 1 WPM");
+            let variance = it.next().map(str::parse).transpose()?;
+            ensure!(it.next().is_none(), "Too many arguments to ADD_BOT");
+            return Ok(ClientMessage::AddBot { wpm, variance });
+        } else if buffer.starts_with(b"REPLAY ") {
+            // REPLAY 12 4
+            let buffer = std::str::from_utf8(&buffer[b"REPLAY ".len()..])?;
+            let mut it = buffer.split_ascii_whitespace();
+            let race = it
+                .next()
+                .ok_or_else(|| anyhow!("REPLAY needs a race id"))?
+                .parse()?;
+            let speed = it.next().map(str::parse).transpose()?;
+            ensure!(speed != Some(0), "Replays can't be played at speed 0");
+            ensure!(it.next().is_none(), "Too many arguments to REPLAY");
+            return Ok(ClientMessage::Replay { race, speed });
+        }
+        Err(anyhow!("Invalid command found"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Mistakes;
+
+    fn round_trip(message: ClientMessage) {
+        let line = message.encode();
+        assert!(line.ends_with('\n'), "{:?}", line);
+        assert_eq!(ClientMessage::decode(line.as_bytes()).unwrap(), message);
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        let code = LobbyCode::new(b"ABCDE").unwrap();
+        for message in vec![
+            ClientMessage::Start,
+            ClientMessage::Create,
+            ClientMessage::State(PlayerState::default()),
+            ClientMessage::State(PlayerState {
+                current_word: 3,
+                current_completed_character: 17,
+                did_make_mistake: true,
+                mistakes: Mistakes {
+                    position: Some(17),
+                    uncorrected: 1,
+                    corrected: 4,
+                    backspaces: 6,
+                },
+            }),
+            ClientMessage::Join(code),
+            ClientMessage::JoinRandom,
+            ClientMessage::Restart,
+            ClientMessage::Words,
+            ClientMessage::Pong(u64::MAX),
+            ClientMessage::Kick(2),
+            ClientMessage::Ban(3),
+            ClientMessage::Promote(4),
+            ClientMessage::Chat("gg, wp \\o/".to_owned()),
+            ClientMessage::Name("ashkan".to_owned()),
+            ClientMessage::AddBot {
+                wpm: 80,
+                variance: None,
+            },
+            ClientMessage::AddBot {
+                wpm: 120,
+                variance: Some(15),
+            },
+            ClientMessage::AddGhost { record: false },
+            ClientMessage::AddGhost { record: true },
+            ClientMessage::Daily,
+            ClientMessage::DailyBoard,
+            ClientMessage::Stats,
+            ClientMessage::Replay {
+                race: 12,
+                speed: None,
+            },
+            ClientMessage::Replay {
+                race: 12,
+                speed: Some(4),
+            },
+        ] {
+            round_trip(message);
+        }
+    }
+
+    #[test]
+    fn decodes_lines_without_newline() {
+        assert_eq!(
+            ClientMessage::decode(b"STATE 1  30 1").unwrap(),
+            ClientMessage::State(PlayerState {
+                current_word: 1,
+                current_completed_character: 30,
+                did_make_mistake: true,
+                ..PlayerState::default()
+            })
+        );
+        assert_eq!(
+            ClientMessage::decode(b"STATE 1 30 0 - 2 1 1").unwrap(),
+            ClientMessage::State(PlayerState {
+                current_word: 1,
+                current_completed_character: 30,
+                did_make_mistake: false,
+                mistakes: Mistakes {
+                    position: None,
+                    uncorrected: 2,
+                    corrected: 1,
+                    backspaces: 1,
+                },
+            })
+        );
+        assert_eq!(
+            ClientMessage::decode(b"JOIN RANDOM").unwrap(),
+            ClientMessage::JoinRandom
+        );
+    }
+
+    #[test]
+    fn truncates_long_chat() {
+        let long = "a".repeat(MAX_CHAT_LENGTH + 10);
+        match ClientMessage::decode(format!("CHAT {}", long).as_bytes()).unwrap() {
+            ClientMessage::Chat(message) => assert_eq!(message.len(), MAX_CHAT_LENGTH),
+            message => panic!("{:?}", message),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_messages() {
+        for line in &[
+            &b""[..],
+            b"STATE 1 2",
+            b"STATE a b c",
+            b"JOIN AB-CD",
+            b"KICK me",
+            b"CHAT    ",
+            b"NAME two words",
+            b"NAME thisnameiswaytoolong",
+            b"ADD_BOT",
+            b"ADD_BOT 0",
+            b"ADD_BOT 80 10 5",
+            b"ADD_BOT fast",
+            b"ADD_GHOST BEST",
+            b"REPLAY",
+            b"REPLAY 12 0",
+            b"REPLAY last",
+            b"START\nCREATE",
+            b"DANCE",
+        ] {
+            assert!(
+                ClientMessage::decode(line).is_err(),
+                "{:?}",
+                String::from_utf8_lossy(line)
+            );
+        }
+    }
+}
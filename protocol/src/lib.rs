@@ -0,0 +1,276 @@
+//! The line based typeracer protocol, shared by the server and anything that talks to it.
+//!
+//! Every message is a single line of text ending in `\n`. `ClientMessage`s go from clients to the
+//! server and `ServerMessage`s come back. Both `encode` into a complete line and `decode` a line
+//! with or without its trailing newline, so `decode(encode(message)) == message`.
+use anyhow::{ensure, Result};
+use std::fmt;
+
+mod client;
+mod server;
+
+pub use client::ClientMessage;
+pub use server::ServerMessage;
+
+pub type ClientId = u64;
+
+/// Longest lobby code we support.
+pub const MAX_LOBBY_CODE_LENGTH: usize = 16;
+/// Longer CHAT messages are truncated.
+pub const MAX_CHAT_LENGTH: usize = 256;
+pub const MAX_NAME_LENGTH: usize = 16;
+
+/// Up to `MAX_LOBBY_CODE_LENGTH` ASCII letters or digits, stored inline so it stays `Copy`.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LobbyCode {
+    bytes: [u8; MAX_LOBBY_CODE_LENGTH],
+    len: u8,
+}
+
+impl LobbyCode {
+    /// Not a valid code, for when one is needed before we know which lobby it'll be.
+    pub const EMPTY: LobbyCode = LobbyCode {
+        bytes: [0; MAX_LOBBY_CODE_LENGTH],
+        len: 0,
+    };
+
+    pub fn new(code: &[u8]) -> Result<Self> {
+        ensure!(
+            !code.is_empty() && code.len() <= MAX_LOBBY_CODE_LENGTH,
+            "Invalid lobby code length: {}",
+            code.len()
+        );
+        ensure!(
+            code.iter().all(u8::is_ascii_alphanumeric),
+            "Lobby codes can only contain letters and digits"
+        );
+        let mut bytes = [0; MAX_LOBBY_CODE_LENGTH];
+        bytes[..code.len()].copy_from_slice(code);
+        Ok(LobbyCode {
+            bytes,
+            len: code.len() as u8,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever built from ASCII.
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap()
+    }
+
+    /// The same code in upper case, which is how servers generate them, so typed codes can match
+    /// regardless of case.
+    pub fn to_ascii_uppercase(&self) -> Self {
+        let mut code = *self;
+        code.bytes.make_ascii_uppercase();
+        code
+    }
+}
+
+impl fmt::Debug for LobbyCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for LobbyCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Information needed to:
+/// - Check for win condition.
+/// - Render current progress to other players.
+/// - Work out how accurately they typed.
+#[derive(Eq, PartialEq, Debug, Default, Copy, Clone)]
+pub struct PlayerState {
+    pub current_word: usize,
+    pub current_completed_character: usize,
+    pub did_make_mistake: bool,
+    pub mistakes: Mistakes,
+}
+
+/// A player's mistakes so far in the race. Clients that don't keep track leave them all at 0.
+#[derive(Eq, PartialEq, Debug, Default, Copy, Clone)]
+pub struct Mistakes {
+    /// Character in the current word the mistake being made is at.
+    pub position: Option<usize>,
+    /// Wrong keys typed over with the right one, rather than deleted.
+    pub uncorrected: u32,
+    /// Wrong keys deleted with backspace.
+    pub corrected: u32,
+    /// Every backspace, whether or not there was a mistake to delete.
+    pub backspaces: u32,
+}
+
+/// The mistakes a player made on one word of the text.
+#[derive(Eq, PartialEq, Debug, Default, Copy, Clone)]
+pub struct WordMistakes {
+    pub word: usize,
+    pub uncorrected: u32,
+    pub corrected: u32,
+    pub backspaces: u32,
+}
+
+/// What a player's finished races say about their typing. Each list has the worst first.
+#[derive(Eq, PartialEq, Debug, Default, Clone)]
+pub struct TypingStats {
+    pub races: u32,
+    /// Pairs of letters with the average milliseconds from the first key to the second.
+    pub bigrams: Vec<(String, u64)>,
+    /// Characters with how many times they were missed and how many times they were typed.
+    pub characters: Vec<(char, u32, u32)>,
+    /// Words with the average WPM they were typed at.
+    pub words: Vec<(String, u32)>,
+}
+
+impl PlayerState {
+    /// `<word> <character> <mistake> <position> <uncorrected> <corrected> <backspaces>`, as sent
+    /// in STATE messages, with a position of `-` when there's no mistake.
+    fn encode(&self) -> String {
+        let mistakes = &self.mistakes;
+        format!(
+            "{} {} {} {} {} {} {}",
+            self.current_word,
+            self.current_completed_character,
+            self.did_make_mistake as u8,
+            mistakes
+                .position
+                .map_or("-".to_owned(), |position| position.to_string()),
+            mistakes.uncorrected,
+            mistakes.corrected,
+            mistakes.backspaces
+        )
+    }
+
+    /// Reads the fields written by `encode`, ignoring any extra whitespace.
+    fn decode<'a>(it: &mut impl Iterator<Item = &'a [u8]>) -> Result<Self> {
+        let mut it = it.filter(|s| !s.is_empty());
+        macro_rules! parse_next {
+            ($ty:ty) => {{
+                let field = it
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("Reached end of input while parsing STATE"))?;
+                std::str::from_utf8(field)?.parse::<$ty>()?
+            }};
+        }
+        // TODO(ashkan): wrap error message with context.
+        let mut state = PlayerState {
+            current_word: parse_next!(usize),
+            current_completed_character: parse_next!(usize),
+            did_make_mistake: parse_next!(u8) == 1,
+            mistakes: Mistakes::default(),
+        };
+        // Older clients stop after the mistake flag.
+        if let Some(position) = it.next() {
+            state.mistakes = Mistakes {
+                position: match position {
+                    b"-" => None,
+                    position => Some(std::str::from_utf8(position)?.parse()?),
+                },
+                uncorrected: parse_next!(u32),
+                corrected: parse_next!(u32),
+                backspaces: parse_next!(u32),
+            };
+        }
+        Ok(state)
+    }
+}
+
+/// Strips the newline `encode` adds and makes sure nothing else could end the line early.
+fn strip_newline(line: &[u8]) -> Result<&[u8]> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    ensure!(!line.contains(&b'\n'), "Messages are a single line");
+    Ok(line)
+}
+
+/// Splits the complete lines off the front of `buffer`, leaving any partial line behind.
+pub fn drain_lines(buffer: &mut Vec<u8>, max_line_length: usize) -> Result<Vec<Vec<u8>>> {
+    let mut lines = vec![];
+    let mut last_pos = 0;
+    for (i, &c) in buffer.iter().enumerate() {
+        if c == b'\n' {
+            ensure!(
+                i - last_pos <= max_line_length,
+                "Line of {} bytes is longer than the maximum of {}",
+                i - last_pos,
+                max_line_length
+            );
+            lines.push(buffer[last_pos..i].to_vec());
+            last_pos = i + 1;
+        }
+    }
+    ensure!(
+        buffer.len() - last_pos <= max_line_length,
+        "Unterminated line is longer than the maximum of {}",
+        max_line_length
+    );
+    buffer.drain(..last_pos);
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uppercases_codes() {
+        assert_eq!(
+            LobbyCode::new(b"abC12").unwrap().to_ascii_uppercase(),
+            LobbyCode::new(b"ABC12").unwrap()
+        );
+    }
+
+    #[test]
+    fn drain_lines_keeps_partial_line() {
+        let mut buffer = b"STA".to_vec();
+        assert!(drain_lines(&mut buffer, 16).unwrap().is_empty());
+        assert_eq!(buffer, b"STA");
+        buffer.extend_from_slice(b"TE 1 2 0\n");
+        assert_eq!(
+            drain_lines(&mut buffer, 16).unwrap(),
+            vec![b"STATE 1 2 0".to_vec()]
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn drain_lines_splits_merged_lines() {
+        let mut buffer = b"START\nCREATE\n\nJOIN AB".to_vec();
+        assert_eq!(
+            drain_lines(&mut buffer, 16).unwrap(),
+            vec![b"START".to_vec(), b"CREATE".to_vec(), b"".to_vec()]
+        );
+        assert_eq!(buffer, b"JOIN AB");
+    }
+
+    #[test]
+    fn drain_lines_allows_exactly_max_length() {
+        let mut buffer = b"0123456789\n0123456789".to_vec();
+        assert_eq!(
+            drain_lines(&mut buffer, 10).unwrap(),
+            vec![b"0123456789".to_vec()]
+        );
+        assert_eq!(buffer, b"0123456789");
+    }
+
+    #[test]
+    fn drain_lines_rejects_oversized_line() {
+        let mut buffer = b"START\n01234567890\n".to_vec();
+        assert!(drain_lines(&mut buffer, 10).is_err());
+    }
+
+    #[test]
+    fn drain_lines_rejects_oversized_partial_line() {
+        let mut buffer = b"START\n01234567890".to_vec();
+        assert!(drain_lines(&mut buffer, 10).is_err());
+    }
+
+    #[test]
+    fn lobby_codes_are_short_and_alphanumeric() {
+        assert_eq!(LobbyCode::new(b"ABC12").unwrap().as_str(), "ABC12");
+        assert!(LobbyCode::new(b"").is_err());
+        assert!(LobbyCode::new(&[b'A'; MAX_LOBBY_CODE_LENGTH + 1]).is_err());
+        assert!(LobbyCode::new(b"AB CD").is_err());
+    }
+}
@@ -0,0 +1,576 @@
+//! Messages sent from the server to clients.
+use crate::{strip_newline, ClientId, LobbyCode, PlayerState, TypingStats, WordMistakes};
+use anyhow::{anyhow, bail, ensure, Result};
+use std::convert::TryInto;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerMessage {
+    /// The first message on every connection, with the id we'll be known by.
+    Connected(ClientId),
+    Created(LobbyCode),
+    /// There was no free lobby code to give a new lobby.
+    CreateFailed,
+    Joined(LobbyCode),
+    JoinFailed {
+        banned: bool,
+    },
+    /// The race text.
+    Words(Vec<String>),
+    State {
+        client_id: ClientId,
+        state: PlayerState,
+    },
+    /// Seconds until the race starts.
+    Countdown(u64),
+    Starting,
+    Finished(ClientId),
+    NewLeader(ClientId),
+    PlayerDisconnected(ClientId),
+    PlayerKicked(ClientId),
+    /// Answer with a PONG carrying the same nonce.
+    Ping(u64),
+    Latency {
+        client_id: ClientId,
+        millis: u64,
+    },
+    /// Seconds left until the server goes away.
+    ServerShutdown(u64),
+    /// We were removed from our lobby, with a human readable reason.
+    Kicked(String),
+    LobbyClosed,
+    /// Seconds until our lobby is closed for sitting idle, unless something happens in it.
+    LobbyExpiring(u64),
+    Announcement(String),
+    /// Sent instead of CONNECTED to banned addresses.
+    Banned,
+    Name {
+        client_id: ClientId,
+        name: String,
+    },
+    Chat {
+        client_id: ClientId,
+        name: String,
+        message: String,
+    },
+    Throttled {
+        class: String,
+        violations: u32,
+        max_violations: u32,
+    },
+    /// We broke the framing rules and are about to be disconnected.
+    ProtocolError(String),
+    /// A command that makes no sense in the state our lobby is in, like START during a race. It was
+    /// ignored and we're still connected.
+    Invalid {
+        command: String,
+        state: String,
+    },
+    /// There's no recorded run on this text to race a ghost of.
+    NoGhost,
+    /// Every word a player who just finished made mistakes or pressed backspace on.
+    Accuracy {
+        client_id: ClientId,
+        words: Vec<WordMistakes>,
+    },
+    /// The id the race that's just starting is recorded under, for REPLAY.
+    Race(u64),
+    /// A REPLAY has played to the end.
+    ReplayEnd(u64),
+    /// There's no recording of the race REPLAY asked for.
+    NoReplay(u64),
+    /// Our lobby is racing the daily challenge for this date, the text follows in WORDS.
+    Daily(String),
+    /// The day's fastest finishes with their times in milliseconds, fastest first.
+    DailyBoard {
+        date: String,
+        finishes: Vec<(String, u64)>,
+    },
+    Stats(TypingStats),
+}
+
+impl ServerMessage {
+    /// The full line to send, including the trailing newline.
+    pub fn encode(&self) -> String {
+        match self {
+            ServerMessage::Connected(id) => format!("CONNECTED {}\n", id),
+            ServerMessage::Created(code) => format!("CREATED {}\n", code),
+            ServerMessage::CreateFailed => "CREATE_FAILED\n".to_owned(),
+            ServerMessage::Joined(code) => format!("JOINED {}\n", code),
+            ServerMessage::JoinFailed { banned: false } => "JOIN_FAILED\n".to_owned(),
+            ServerMessage::JoinFailed { banned: true } => "JOIN_FAILED banned\n".to_owned(),
+            ServerMessage::Words(words) => format!("WORDS {}\n", words.join(" ")),
+            ServerMessage::State { client_id, state } => {
+                format!("STATE {} {}\n", client_id, state.encode())
+            }
+            ServerMessage::Countdown(seconds) => format!("COUNTDOWN {}\n", seconds),
+            ServerMessage::Starting => "STARTING\n".to_owned(),
+            ServerMessage::Finished(id) => format!("FINISHED {}\n", id),
+            ServerMessage::NewLeader(id) => format!("NEW_LEADER {}\n", id),
+            ServerMessage::PlayerDisconnected(id) => format!("PLAYER_DISCONNECTED {}\n", id),
+            ServerMessage::PlayerKicked(id) => format!("PLAYER_KICKED {}\n", id),
+            ServerMessage::Ping(nonce) => format!("PING {}\n", nonce),
+            ServerMessage::Latency { client_id, millis } => {
+                format!("LATENCY {} {}\n", client_id, millis)
+            }
+            ServerMessage::ServerShutdown(seconds) => format!("SERVER_SHUTDOWN {}\n", seconds),
+            ServerMessage::Kicked(reason) => format!("KICKED {}\n", reason),
+            ServerMessage::LobbyClosed => "LOBBY_CLOSED\n".to_owned(),
+            ServerMessage::LobbyExpiring(seconds) => format!("LOBBY_EXPIRING {}\n", seconds),
+            ServerMessage::Announcement(message) => format!("ANNOUNCEMENT {}\n", message),
+            ServerMessage::Banned => "BANNED\n".to_owned(),
+            ServerMessage::Name { client_id, name } => format!("NAME {} {}\n", client_id, name),
+            ServerMessage::Chat {
+                client_id,
+                name,
+                message,
+            } => format!("CHAT {} {} {}\n", client_id, name, escape_chat(message)),
+            ServerMessage::Throttled {
+                class,
+                violations,
+                max_violations,
+            } => format!("THROTTLED {} {} {}\n", class, violations, max_violations),
+            ServerMessage::ProtocolError(reason) => format!("PROTOCOL_ERROR {}\n", reason),
+            ServerMessage::Invalid { command, state } => {
+                format!("INVALID {} {}\n", command, state)
+            }
+            ServerMessage::NoGhost => "NO_GHOST\n".to_owned(),
+            ServerMessage::Accuracy { client_id, words } => {
+                let mut line = format!("ACCURACY {}", client_id);
+                for word in words {
+                    line += &format!(
+                        " {} {} {} {}",
+                        word.word, word.uncorrected, word.corrected, word.backspaces
+                    );
+                }
+                line + "\n"
+            }
+            ServerMessage::Race(id) => format!("RACE {}\n", id),
+            ServerMessage::ReplayEnd(id) => format!("REPLAY_END {}\n", id),
+            ServerMessage::NoReplay(id) => format!("NO_REPLAY {}\n", id),
+            ServerMessage::Daily(date) => format!("DAILY {}\n", date),
+            ServerMessage::DailyBoard { date, finishes } => {
+                let mut line = format!("DAILY_BOARD {}", date);
+                for (name, millis) in finishes {
+                    line += &format!(" {} {}", name, millis);
+                }
+                line + "\n"
+            }
+            ServerMessage::Stats(stats) => {
+                let mut line = format!("STATS {}", stats.races);
+                for (bigram, millis) in &stats.bigrams {
+                    line += &format!(" bigram {} {}", bigram, millis);
+                }
+                for (character, missed, typed) in &stats.characters {
+                    line += &format!(" char {} {} {}", character, missed, typed);
+                }
+                for (word, wpm) in &stats.words {
+                    line += &format!(" word {} {}", word, wpm);
+                }
+                line + "\n"
+            }
+        }
+    }
+
+    pub fn decode(line: &[u8]) -> Result<Self> {
+        let line = std::str::from_utf8(strip_newline(line)?)?;
+        let (command, rest) = match line.find(' ') {
+            Some(i) => (&line[..i], Some(&line[i + 1..])),
+            None => (line, None),
+        };
+        let args = || rest.ok_or_else(|| anyhow!("{} needs arguments", command));
+        Ok(match command {
+            "CONNECTED" => ServerMessage::Connected(args()?.parse()?),
+            "CREATED" => ServerMessage::Created(LobbyCode::new(args()?.as_bytes())?),
+            "CREATE_FAILED" => ServerMessage::CreateFailed,
+            "JOINED" => ServerMessage::Joined(LobbyCode::new(args()?.as_bytes())?),
+            "JOIN_FAILED" => match rest {
+                None => ServerMessage::JoinFailed { banned: false },
+                Some("banned") => ServerMessage::JoinFailed { banned: true },
+                Some(reason) => bail!("Unknown JOIN_FAILED reason: {:?}", reason),
+            },
+            "WORDS" => ServerMessage::Words(
+                args()?
+                    .split(' ')
+                    .filter(|word| !word.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+            ),
+            "STATE" => {
+                let (client_id, state) = split_field(args()?);
+                let mut it = state.as_bytes().split(|c| c.is_ascii_whitespace());
+                ServerMessage::State {
+                    client_id: client_id.parse()?,
+                    state: PlayerState::decode(&mut it)?,
+                }
+            }
+            "COUNTDOWN" => ServerMessage::Countdown(args()?.parse()?),
+            "STARTING" => ServerMessage::Starting,
+            "FINISHED" => ServerMessage::Finished(args()?.parse()?),
+            "NEW_LEADER" => ServerMessage::NewLeader(args()?.parse()?),
+            "PLAYER_DISCONNECTED" => ServerMessage::PlayerDisconnected(args()?.parse()?),
+            "PLAYER_KICKED" => ServerMessage::PlayerKicked(args()?.parse()?),
+            "PING" => ServerMessage::Ping(args()?.parse()?),
+            "LATENCY" => {
+                let (client_id, millis) = split_field(args()?);
+                ServerMessage::Latency {
+                    client_id: client_id.parse()?,
+                    millis: millis.parse()?,
+                }
+            }
+            "SERVER_SHUTDOWN" => ServerMessage::ServerShutdown(args()?.parse()?),
+            "KICKED" => ServerMessage::Kicked(args()?.to_owned()),
+            "LOBBY_CLOSED" => ServerMessage::LobbyClosed,
+            "LOBBY_EXPIRING" => ServerMessage::LobbyExpiring(args()?.parse()?),
+            "ANNOUNCEMENT" => ServerMessage::Announcement(args()?.to_owned()),
+            "BANNED" => ServerMessage::Banned,
+            "NAME" => {
+                let (client_id, name) = split_field(args()?);
+                ServerMessage::Name {
+                    client_id: client_id.parse()?,
+                    name: name.to_owned(),
+                }
+            }
+            "CHAT" => {
+                let (client_id, rest) = split_field(args()?);
+                let (name, message) = split_field(rest);
+                ServerMessage::Chat {
+                    client_id: client_id.parse()?,
+                    name: name.to_owned(),
+                    message: unescape_chat(message)?,
+                }
+            }
+            "THROTTLED" => {
+                let (class, rest) = split_field(args()?);
+                let (violations, max_violations) = split_field(rest);
+                ServerMessage::Throttled {
+                    class: class.to_owned(),
+                    violations: violations.parse()?,
+                    max_violations: max_violations.parse()?,
+                }
+            }
+            "PROTOCOL_ERROR" => ServerMessage::ProtocolError(args()?.to_owned()),
+            "INVALID" => {
+                let (command, state) = split_field(args()?);
+                ensure!(!state.is_empty(), "INVALID needs a state");
+                ServerMessage::Invalid {
+                    command: command.to_owned(),
+                    state: state.to_owned(),
+                }
+            }
+            "NO_GHOST" => ServerMessage::NoGhost,
+            "ACCURACY" => {
+                // ACCURACY 3 0 1 0 0 4 0 2 3
+                let mut it = args()?.split(' ');
+                let client_id = it.next().unwrap_or_default().parse()?;
+                let fields = it
+                    .map(str::parse)
+                    .collect::<std::result::Result<Vec<u64>, _>>()?;
+                ensure!(
+                    fields.len() % 4 == 0,
+                    "ACCURACY needs four numbers for every word"
+                );
+                ServerMessage::Accuracy {
+                    client_id,
+                    words: fields
+                        .chunks(4)
+                        .map(|word| {
+                            Ok(WordMistakes {
+                                word: word[0].try_into()?,
+                                uncorrected: word[1].try_into()?,
+                                corrected: word[2].try_into()?,
+                                backspaces: word[3].try_into()?,
+                            })
+                        })
+                        .collect::<Result<_>>()?,
+                }
+            }
+            "RACE" => ServerMessage::Race(args()?.parse()?),
+            "REPLAY_END" => ServerMessage::ReplayEnd(args()?.parse()?),
+            "NO_REPLAY" => ServerMessage::NoReplay(args()?.parse()?),
+            "DAILY" => ServerMessage::Daily(args()?.to_owned()),
+            "DAILY_BOARD" => {
+                // DAILY_BOARD 2026-10-18 ashkan 41250 norcalli 43800
+                let mut it = args()?.split(' ');
+                let date = it.next().unwrap_or_default().to_owned();
+                let fields: Vec<_> = it.collect();
+                ensure!(
+                    fields.len() % 2 == 0,
+                    "DAILY_BOARD needs a time for every name"
+                );
+                ServerMessage::DailyBoard {
+                    date,
+                    finishes: fields
+                        .chunks(2)
+                        .map(|pair| Ok((pair[0].to_owned(), pair[1].parse()?)))
+                        .collect::<Result<_>>()?,
+                }
+            }
+            "STATS" => {
+                // STATS 3 bigram th 180 char q 2 40 word neovim 52
+                let mut it = args()?.split(' ');
+                let mut stats = TypingStats {
+                    races: it.next().unwrap_or_default().parse()?,
+                    ..TypingStats::default()
+                };
+                let mut field = || {
+                    it.next()
+                        .ok_or_else(|| anyhow!("STATS ended part way through an entry"))
+                };
+                while let Ok(kind) = field() {
+                    match kind {
+                        "bigram" => {
+                            let bigram = field()?;
+                            ensure!(bigram.chars().count() == 2, "Invalid bigram: {:?}", bigram);
+                            stats.bigrams.push((bigram.to_owned(), field()?.parse()?));
+                        }
+                        "char" => stats.characters.push((
+                            field()?.parse()?,
+                            field()?.parse()?,
+                            field()?.parse()?,
+                        )),
+                        "word" => stats.words.push((field()?.to_owned(), field()?.parse()?)),
+                        _ => bail!("Unknown STATS entry: {:?}", kind),
+                    }
+                }
+                ServerMessage::Stats(stats)
+            }
+            _ => bail!("Unknown server message: {:?}", command),
+        })
+    }
+}
+
+/// Splits off one space separated field, returning it and whatever follows.
+fn split_field(s: &str) -> (&str, &str) {
+    match s.find(' ') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, ""),
+    }
+}
+
+/// Escapes chat text so it fits on one protocol line and can't smuggle control sequences to
+/// terminal clients.
+fn escape_chat(message: &str) -> String {
+    let mut escaped = String::with_capacity(message.len());
+    for c in message.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.extend(c.escape_unicode()),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Undoes `escape_chat`.
+fn unescape_chat(escaped: &str) -> Result<String> {
+    let mut message = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            message.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => message.push('\\'),
+            Some('n') => message.push('\n'),
+            Some('r') => message.push('\r'),
+            Some('t') => message.push('\t'),
+            Some('u') => {
+                ensure!(chars.next() == Some('{'), "Invalid unicode escape in CHAT");
+                let mut hex = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) if c.is_ascii_hexdigit() && hex.len() < 6 => hex.push(c),
+                        _ => bail!("Invalid unicode escape in CHAT"),
+                    }
+                }
+                let code = u32::from_str_radix(&hex, 16)?;
+                message.push(
+                    std::char::from_u32(code)
+                        .ok_or_else(|| anyhow!("Invalid unicode escape in CHAT"))?,
+                );
+            }
+            other => bail!("Invalid escape in CHAT: {:?}", other),
+        }
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Mistakes;
+
+    fn round_trip(message: ServerMessage) {
+        let line = message.encode();
+        assert!(line.ends_with('\n'), "{:?}", line);
+        assert_eq!(
+            line.matches('\n').count(),
+            1,
+            "{:?} isn't a single line",
+            line
+        );
+        assert_eq!(ServerMessage::decode(line.as_bytes()).unwrap(), message);
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        let code = LobbyCode::new(b"ABCDE").unwrap();
+        for message in vec![
+            ServerMessage::Connected(1),
+            ServerMessage::Created(code),
+            ServerMessage::CreateFailed,
+            ServerMessage::Joined(code),
+            ServerMessage::JoinFailed { banned: false },
+            ServerMessage::JoinFailed { banned: true },
+            ServerMessage::Words(vec!["chicken".to_owned(), "neovim".to_owned()]),
+            ServerMessage::Words(vec![]),
+            ServerMessage::State {
+                client_id: 2,
+                state: PlayerState {
+                    current_word: 4,
+                    current_completed_character: 1,
+                    did_make_mistake: true,
+                    ..PlayerState::default()
+                },
+            },
+            ServerMessage::State {
+                client_id: 2,
+                state: PlayerState {
+                    current_word: 4,
+                    current_completed_character: 1,
+                    did_make_mistake: true,
+                    mistakes: Mistakes {
+                        position: Some(1),
+                        uncorrected: 2,
+                        corrected: 3,
+                        backspaces: 5,
+                    },
+                },
+            },
+            ServerMessage::Countdown(5),
+            ServerMessage::Starting,
+            ServerMessage::Finished(3),
+            ServerMessage::NewLeader(4),
+            ServerMessage::PlayerDisconnected(5),
+            ServerMessage::PlayerKicked(6),
+            ServerMessage::Ping(7),
+            ServerMessage::Latency {
+                client_id: 8,
+                millis: 42,
+            },
+            ServerMessage::ServerShutdown(30),
+            ServerMessage::Kicked("Removed by the lobby leader".to_owned()),
+            ServerMessage::LobbyClosed,
+            ServerMessage::LobbyExpiring(10),
+            ServerMessage::Announcement("Restarting in 5 minutes".to_owned()),
+            ServerMessage::Banned,
+            ServerMessage::Name {
+                client_id: 9,
+                name: "ashkan".to_owned(),
+            },
+            ServerMessage::Chat {
+                client_id: 10,
+                name: "P10".to_owned(),
+                message: "multi\nline \\ with\ttabs and \u{1b}[31mescapes".to_owned(),
+            },
+            ServerMessage::Throttled {
+                class: "CHAT".to_owned(),
+                violations: 1,
+                max_violations: 10,
+            },
+            ServerMessage::ProtocolError("Line of 4096 bytes is too long".to_owned()),
+            ServerMessage::Invalid {
+                command: "START".to_owned(),
+                state: "RaceRunning".to_owned(),
+            },
+            ServerMessage::NoGhost,
+            ServerMessage::Accuracy {
+                client_id: 3,
+                words: vec![],
+            },
+            ServerMessage::Accuracy {
+                client_id: 3,
+                words: vec![
+                    WordMistakes {
+                        word: 0,
+                        uncorrected: 1,
+                        corrected: 0,
+                        backspaces: 0,
+                    },
+                    WordMistakes {
+                        word: 4,
+                        uncorrected: 0,
+                        corrected: 2,
+                        backspaces: 3,
+                    },
+                ],
+            },
+            ServerMessage::Race(11),
+            ServerMessage::ReplayEnd(12),
+            ServerMessage::NoReplay(13),
+            ServerMessage::Daily("2026-10-18".to_owned()),
+            ServerMessage::DailyBoard {
+                date: "2026-10-18".to_owned(),
+                finishes: vec![],
+            },
+            ServerMessage::DailyBoard {
+                date: "2026-10-18".to_owned(),
+                finishes: vec![("ashkan".to_owned(), 41250), ("P3".to_owned(), 43800)],
+            },
+            ServerMessage::Stats(TypingStats::default()),
+            ServerMessage::Stats(TypingStats {
+                races: 3,
+                bigrams: vec![("th".to_owned(), 180), ("ng".to_owned(), 150)],
+                characters: vec![('q', 2, 40), ('\'', 1, 12)],
+                words: vec![("neovim".to_owned(), 52)],
+            }),
+        ] {
+            round_trip(message);
+        }
+    }
+
+    #[test]
+    fn chat_escapes_control_characters() {
+        let line = ServerMessage::Chat {
+            client_id: 1,
+            name: "P1".to_owned(),
+            message: "a\nb\u{7}".to_owned(),
+        }
+        .encode();
+        assert_eq!(line, "CHAT 1 P1 a\\nb\\u{7}\n");
+    }
+
+    #[test]
+    fn rejects_invalid_messages() {
+        for line in &[
+            &b""[..],
+            b"CONNECTED",
+            b"CONNECTED one",
+            b"STATE 1 2 3",
+            b"JOIN_FAILED maybe",
+            b"CHAT 1 P1 \\x",
+            b"CHAT 1 P1 \\u{110000}",
+            b"LATENCY 1",
+            b"INVALID START",
+            b"WINNER 1",
+            b"DAILY_BOARD 2026-10-18 ashkan",
+            b"DAILY_BOARD 2026-10-18 ashkan fast",
+            b"STATS",
+            b"STATS 1 bigram thr 180",
+            b"STATS 1 char q 2",
+            b"STATS 1 word neovim",
+            b"STATS 1 trigram the 200",
+        ] {
+            assert!(
+                ServerMessage::decode(line).is_err(),
+                "{:?}",
+                String::from_utf8_lossy(line)
+            );
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Where in the text each player went wrong. STATE carries running totals of a player's mistakes,
+//! so whatever each update adds is charged to the word they were on when they made it.
+use protocol::{PlayerState, WordMistakes};
+use std::collections::BTreeMap;
+
+#[derive(Default)]
+pub struct Accuracy {
+    /// The previous update, to take the totals in the next one away from.
+    last: PlayerState,
+    words: BTreeMap<usize, WordMistakes>,
+}
+
+impl Accuracy {
+    /// Charges whatever `state` adds to the totals to a word of `text`. Clients can send any
+    /// totals, so counts saturate, and mistakes on words past the end of the text or more
+    /// uncorrected ones than a word has keys are ignored.
+    pub fn update(&mut self, state: PlayerState, text: &[String]) {
+        let (last, new) = (self.last.mistakes, state.mistakes);
+        // Totals only go up over a race, a client that says otherwise just starts again from
+        // its new ones.
+        let uncorrected = new.uncorrected.saturating_sub(last.uncorrected);
+        let corrected = new.corrected.saturating_sub(last.corrected);
+        let backspaces = new.backspaces.saturating_sub(last.backspaces);
+        // Typing over a mistake at the end of a word finishes it, so the mistake belongs to the
+        // word they were on before.
+        let word = self.last.current_word;
+        self.last = state;
+        let keys = match text.get(word) {
+            // The space after it is typed too.
+            Some(text) => text.chars().count() as u32 + 1,
+            None => return,
+        };
+        if uncorrected == 0 && corrected == 0 && backspaces == 0 {
+            return;
+        }
+        let mistakes = self.words.entry(word).or_insert(WordMistakes {
+            word,
+            ..WordMistakes::default()
+        });
+        mistakes.uncorrected = mistakes.uncorrected.saturating_add(uncorrected).min(keys);
+        mistakes.corrected = mistakes.corrected.saturating_add(corrected);
+        mistakes.backspaces = mistakes.backspaces.saturating_add(backspaces);
+    }
+
+    /// Every word with any mistakes or backspaces, in the order they come in the text.
+    pub fn words(&self) -> Vec<WordMistakes> {
+        self.words.values().copied().collect()
+    }
+
+    /// The share of keystrokes that were right, given how many characters the text has.
+    pub fn percent(&self, text_length: usize) -> f64 {
+        let mistakes: u64 = self
+            .words
+            .values()
+            .map(|word| u64::from(word.uncorrected) + u64::from(word.corrected))
+            .sum();
+        let keys = text_length as f64 + mistakes as f64;
+        if keys > 0.0 {
+            100.0 * text_length as f64 / keys
+        } else {
+            100.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::Mistakes;
+
+    fn state(word: usize, uncorrected: u32, corrected: u32, backspaces: u32) -> PlayerState {
+        PlayerState {
+            current_word: word,
+            mistakes: Mistakes {
+                uncorrected,
+                corrected,
+                backspaces,
+                ..Mistakes::default()
+            },
+            ..PlayerState::default()
+        }
+    }
+
+    fn text() -> Vec<String> {
+        ["the", "quick", "brown", "fox"]
+            .iter()
+            .map(|&word| word.to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn charges_mistakes_to_the_word_they_were_made_on() {
+        let text = text();
+        let mut accuracy = Accuracy::default();
+        accuracy.update(state(0, 0, 1, 1), &text);
+        // Typed over a mistake on the last letter of the first word.
+        accuracy.update(state(1, 1, 1, 1), &text);
+        accuracy.update(state(2, 1, 1, 1), &text);
+        accuracy.update(state(2, 1, 1, 3), &text);
+        // A client that lost count doesn't take anything away.
+        accuracy.update(state(3, 0, 0, 0), &text);
+        accuracy.update(state(3, 1, 0, 0), &text);
+        assert_eq!(
+            accuracy.words(),
+            [
+                WordMistakes {
+                    word: 0,
+                    uncorrected: 1,
+                    corrected: 1,
+                    backspaces: 1,
+                },
+                WordMistakes {
+                    word: 2,
+                    uncorrected: 0,
+                    corrected: 0,
+                    backspaces: 2,
+                },
+                WordMistakes {
+                    word: 3,
+                    uncorrected: 1,
+                    corrected: 0,
+                    backspaces: 0,
+                },
+            ]
+        );
+        assert_eq!(accuracy.percent(97), 97.0);
+        assert_eq!(Accuracy::default().percent(0), 100.0);
+    }
+
+    #[test]
+    fn absurd_totals_saturate() {
+        let text = text();
+        let mut accuracy = Accuracy::default();
+        accuracy.update(state(1, u32::MAX, u32::MAX, u32::MAX), &text);
+        accuracy.update(state(1, 0, 0, 0), &text);
+        accuracy.update(state(1, u32::MAX, u32::MAX, u32::MAX), &text);
+        // Past the end of the text there's nothing to charge them to.
+        accuracy.update(state(9, 0, 0, 0), &text);
+        accuracy.update(state(9, u32::MAX, u32::MAX, u32::MAX), &text);
+        assert_eq!(
+            accuracy.words(),
+            [
+                WordMistakes {
+                    word: 0,
+                    uncorrected: 4,
+                    corrected: u32::MAX,
+                    backspaces: u32::MAX,
+                },
+                // No more uncorrected mistakes than "quick " has keys.
+                WordMistakes {
+                    word: 1,
+                    uncorrected: 6,
+                    corrected: u32::MAX,
+                    backspaces: u32::MAX,
+                },
+            ]
+        );
+        let percent = accuracy.percent(19);
+        assert!(percent > 0.0 && percent < 0.001, "{}", percent);
+    }
+}
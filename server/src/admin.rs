@@ -0,0 +1,206 @@
+//! Line based admin interface for operating a running server.
+//!
+//! The server listens on a local unix socket. Each connection sends a single command line, gets
+//! back a plain text response and is then closed by the server. Connections that send more than
+//! a line's worth, hang up early or dawdle are dropped without an answer.
+use anyhow::{bail, ensure, Context, Result};
+use protocol::{ClientId, LobbyCode};
+use std::fs::{DirBuilder, Permissions};
+use std::io::{self, prelude::*};
+use std::net::{IpAddr, Shutdown};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub enum AdminCommand {
+    /// List every lobby with its state and clients.
+    Lobbies,
+    /// Show everything we know about one client.
+    Client(ClientId),
+    Kick(ClientId),
+    /// Kick a client and refuse any further connections from its address.
+    Ban(ClientId),
+    Unban(IpAddr),
+    Close(LobbyCode),
+    /// Broadcast a message to every connected client.
+    Announce(String),
+    /// Export a recorded race as JSON lines.
+    Replay(u64),
+}
+
+/// Listens on `path` with permissions `mode`, replacing a socket left behind by a previous run.
+/// Anything else already there, or a socket another server still answers on, is left alone.
+pub fn bind(path: &Path, mode: u32) -> Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) => {
+            ensure!(
+                metadata.file_type().is_socket(),
+                "{} already exists and isn't a socket",
+                path.display()
+            );
+            ensure!(
+                UnixStream::connect(path).is_err(),
+                "Another server is already listening on {}",
+                path.display()
+            );
+            std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err).with_context(|| format!("Failed to check {}", path.display())),
+    }
+    // Bound straight to `path`, the socket would be open to whatever the umask allows until its
+    // permissions are set. Nobody else can get into this directory, so it's only moved into
+    // place once they are.
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let staging = parent.join(format!(".typeracer-admin-{}", std::process::id()));
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .with_context(|| format!("Failed to create {}", staging.display()))?;
+    let staged = staging.join("socket");
+    let listener = UnixListener::bind(&staged)
+        .map_err(anyhow::Error::from)
+        .and_then(|listener| {
+            std::fs::set_permissions(&staged, Permissions::from_mode(mode))
+                .context("Failed to set admin_socket_mode")?;
+            std::fs::rename(&staged, path)?;
+            Ok(listener)
+        });
+    let _ = std::fs::remove_dir_all(&staging);
+    listener.with_context(|| format!("Failed to bind {}", path.display()))
+}
+
+pub fn parse_admin_command(line: &str) -> Result<AdminCommand> {
+    let line = line.trim();
+    let (command, rest) = match line.find(' ') {
+        Some(i) => (&line[..i], line[i + 1..].trim()),
+        None => (line, ""),
+    };
+    Ok(match command.to_ascii_uppercase().as_str() {
+        "LOBBIES" => AdminCommand::Lobbies,
+        "CLIENT" => AdminCommand::Client(rest.parse()?),
+        "KICK" => AdminCommand::Kick(rest.parse()?),
+        "BAN" => AdminCommand::Ban(rest.parse()?),
+        "UNBAN" => AdminCommand::Unban(rest.parse()?),
+        "CLOSE" => AdminCommand::Close(LobbyCode::new(rest.as_bytes())?.to_ascii_uppercase()),
+        "REPLAY" => AdminCommand::Replay(rest.parse()?),
+        "ANNOUNCE" => {
+            ensure!(!rest.is_empty(), "ANNOUNCE needs a message");
+            AdminCommand::Announce(rest.to_owned())
+        }
+        _ => bail!("Unknown admin command: {:?}", command),
+    })
+}
+
+/// Longest command line we'll wait for, ANNOUNCE messages included.
+const MAX_LINE: usize = 4096;
+/// How long a connection gets to send its command and read the response.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections served at once. Any more are closed straight away.
+pub const MAX_CONNECTIONS: usize = 16;
+
+pub struct AdminConnection {
+    stream: UnixStream,
+    read_buffer: Vec<u8>,
+    /// The response and how much of it has been written, once the command has run.
+    response: Option<(Vec<u8>, usize)>,
+    deadline: Instant,
+}
+
+impl AdminConnection {
+    pub fn new(stream: UnixStream, now: Instant) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(AdminConnection {
+            stream,
+            read_buffer: vec![],
+            response: None,
+            deadline: now + TIMEOUT,
+        })
+    }
+
+    /// Runs the command through `answer` once its line has arrived, then writes back the
+    /// response. Returns whether the connection is done with, and errors if it should be dropped.
+    pub fn poll(&mut self, now: Instant, answer: impl FnOnce(&str) -> String) -> io::Result<bool> {
+        if now >= self.deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Admin connection took too long",
+            ));
+        }
+        if self.response.is_none() {
+            match self.poll_line()? {
+                Some(line) => self.response = Some((answer(&line).into_bytes(), 0)),
+                None => return Ok(false),
+            }
+        }
+        self.poll_write()
+    }
+
+    /// Returns the command line once it has fully arrived.
+    fn poll_line(&mut self) -> io::Result<Option<String>> {
+        let mut chunk = [0; 1024];
+        loop {
+            if let Some(i) = self.read_buffer.iter().position(|&c| c == b'\n') {
+                if i > MAX_LINE {
+                    break;
+                }
+                let line = String::from_utf8_lossy(&self.read_buffer[..i]);
+                return Ok(Some(line.into_owned()));
+            }
+            if self.read_buffer.len() > MAX_LINE {
+                break;
+            }
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Admin connection closed before sending a full line",
+                    ))
+                }
+                Ok(n) => self.read_buffer.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Admin command too long",
+        ))
+    }
+
+    /// Writes as much of the response as the socket takes, and closes it once all of it is out.
+    fn poll_write(&mut self) -> io::Result<bool> {
+        let (response, written) = self.response.as_mut().expect("Polled before responding");
+        while *written < response.len() {
+            match self.stream.write(&response[*written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => *written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        self.stream.shutdown(Shutdown::Both)?;
+        Ok(true)
+    }
+}
+
+/// The `admin` subcommand: send one command to a running server and print the response.
+pub fn run_cli(socket: &Path, command: &[String]) -> Result<()> {
+    ensure!(!command.is_empty(), "No admin command given");
+    let mut stream = UnixStream::connect(socket)?;
+    writeln!(stream, "{}", command.join(" "))?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    print!("{}", response);
+    ensure!(!response.starts_with("ERROR"), "Admin command failed");
+    Ok(())
+}
@@ -0,0 +1,256 @@
+//! Server driven opponents: typists added by the leader with ADD_BOT, and ghosts replaying a
+//! recorded run added with ADD_GHOST.
+//!
+//! Bots have a `ClientId` and sit in `Lobby::clients` like everyone else, but they have no
+//! connection. The main loop ticks them while a race runs and treats their STATE like a player's.
+use crate::ghost::{Replay, Run};
+use protocol::PlayerState;
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+/// Most bots a lobby can have.
+pub const MAX_BOTS: usize = 8;
+/// Fastest a bot can be asked to type.
+pub const MAX_WPM: u32 = 300;
+/// Chance of hitting the wrong key, which costs a keystroke to correct.
+const MISTAKE_RATE: f64 = 0.03;
+/// How long a bot takes to get going once the race starts.
+const REACTION_TIME: Duration = Duration::from_millis(400);
+
+pub enum Bot {
+    Typist(Typist),
+    Ghost { name: String, replay: Replay },
+}
+
+impl Bot {
+    /// Without a variance, typists drift by a tenth of their speed.
+    pub fn typist(wpm: u32, variance: Option<u32>) -> Self {
+        Bot::Typist(Typist::new(wpm, variance))
+    }
+
+    pub fn ghost(run: &Run) -> Self {
+        Bot::Ghost {
+            name: format!("{}(ghost)", run.player),
+            replay: Replay::new(run),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Bot::Typist(typist) => format!("Bot{}", typist.wpm),
+            Bot::Ghost { name, .. } => name.clone(),
+        }
+    }
+
+    pub fn state(&self) -> PlayerState {
+        match self {
+            Bot::Typist(typist) => typist.state,
+            Bot::Ghost { replay, .. } => replay.state(),
+        }
+    }
+
+    pub fn start(&mut self, now: Instant) {
+        match self {
+            Bot::Typist(typist) => typist.start(now),
+            Bot::Ghost { replay, .. } => replay.start(now),
+        }
+    }
+
+    /// Back to the start of the text, waiting for the next race.
+    pub fn reset(&mut self) {
+        match self {
+            Bot::Typist(typist) => typist.reset(),
+            Bot::Ghost { replay, .. } => replay.reset(),
+        }
+    }
+
+    /// Moves the bot along to where it should be by `now`, returning the new state if it changed.
+    pub fn tick(
+        &mut self,
+        words: &[String],
+        now: Instant,
+        rng: &mut impl Rng,
+    ) -> Option<PlayerState> {
+        match self {
+            Bot::Typist(typist) => typist.tick(words, now, rng),
+            Bot::Ghost { replay, .. } => replay.tick(now),
+        }
+    }
+}
+
+pub struct Typist {
+    wpm: u32,
+    variance: u32,
+    mistake_rate: f64,
+    state: PlayerState,
+    /// When the next key gets pressed, if the race is on and there's anything left to type.
+    next_key: Option<Instant>,
+    /// The space after a word takes a keystroke but doesn't show up in STATE.
+    at_space: bool,
+}
+
+impl Typist {
+    fn new(wpm: u32, variance: Option<u32>) -> Self {
+        Typist {
+            wpm,
+            variance: variance.unwrap_or(wpm / 10).min(wpm),
+            mistake_rate: MISTAKE_RATE,
+            state: PlayerState::default(),
+            next_key: None,
+            at_space: false,
+        }
+    }
+
+    fn start(&mut self, now: Instant) {
+        self.reset();
+        self.next_key = Some(now + REACTION_TIME);
+    }
+
+    fn reset(&mut self) {
+        self.state = PlayerState::default();
+        self.at_space = false;
+        self.next_key = None;
+    }
+
+    /// Presses every key that's due by `now`, returning the new state if it changed.
+    fn tick(&mut self, words: &[String], now: Instant, rng: &mut impl Rng) -> Option<PlayerState> {
+        let mut changed = false;
+        while let Some(due) = self.next_key.filter(|&due| due <= now) {
+            let word = match words.get(self.state.current_word) {
+                Some(word) => word,
+                None => {
+                    self.next_key = None;
+                    break;
+                }
+            };
+            if self.at_space {
+                self.at_space = false;
+            } else if !self.state.did_make_mistake && rng.gen_bool(self.mistake_rate) {
+                self.state.did_make_mistake = true;
+                self.state.mistakes.position = Some(self.state.current_completed_character);
+                changed = true;
+            } else {
+                // Bots never backspace, they type the right key over the wrong one.
+                if self.state.did_make_mistake {
+                    self.state.mistakes.uncorrected += 1;
+                    self.state.mistakes.position = None;
+                }
+                self.state.did_make_mistake = false;
+                self.state.current_completed_character += 1;
+                if self.state.current_completed_character >= word.len() {
+                    self.state.current_word += 1;
+                    self.state.current_completed_character = 0;
+                    self.at_space = self.state.current_word < words.len();
+                }
+                changed = true;
+            }
+            self.next_key = Some(due + self.key_delay(rng));
+        }
+        if changed {
+            Some(self.state)
+        } else {
+            None
+        }
+    }
+
+    /// Each key is typed at a speed picked from `wpm ± variance`.
+    fn key_delay(&self, rng: &mut impl Rng) -> Duration {
+        let low = self.wpm.saturating_sub(self.variance).max(1);
+        let wpm = rng.gen_range(low, self.wpm + self.variance + 1);
+        // One "word" is 5 characters.
+        Duration::from_secs_f64(60.0 / (wpm as f64 * 5.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Types at exactly `wpm`, hitting the wrong key with the given chance.
+    fn bot(wpm: u32, mistake_rate: f64) -> Typist {
+        Typist {
+            mistake_rate,
+            ..Typist::new(wpm, Some(0))
+        }
+    }
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|&word| word.to_owned()).collect()
+    }
+
+    #[test]
+    fn waits_for_the_race() {
+        let mut bot = bot(60, 0.0);
+        let now = Instant::now();
+        assert_eq!(
+            bot.tick(&words(&["cow"]), now, &mut rand::thread_rng()),
+            None
+        );
+        bot.start(now);
+        assert_eq!(
+            bot.tick(&words(&["cow"]), now, &mut rand::thread_rng()),
+            None
+        );
+    }
+
+    #[test]
+    fn types_at_its_speed() {
+        // 60 WPM is 5 keys a second.
+        let mut rng = rand::thread_rng();
+        let mut bot = bot(60, 0.0);
+        let text = words(&["to", "be"]);
+        let start = Instant::now();
+        bot.start(start);
+        let key = Duration::from_millis(200);
+        let after = |keys: u32| start + REACTION_TIME + key * keys;
+        assert_eq!(
+            bot.tick(&text, after(0), &mut rng),
+            Some(PlayerState {
+                current_word: 0,
+                current_completed_character: 1,
+                did_make_mistake: false,
+                ..PlayerState::default()
+            })
+        );
+        assert_eq!(
+            bot.tick(&text, after(1), &mut rng),
+            Some(PlayerState {
+                current_word: 1,
+                current_completed_character: 0,
+                did_make_mistake: false,
+                ..PlayerState::default()
+            })
+        );
+        // The space.
+        assert_eq!(bot.tick(&text, after(2), &mut rng), None);
+        assert_eq!(
+            bot.tick(&text, after(4), &mut rng),
+            Some(PlayerState {
+                current_word: 2,
+                current_completed_character: 0,
+                did_make_mistake: false,
+                ..PlayerState::default()
+            })
+        );
+        assert_eq!(bot.tick(&text, after(10), &mut rng), None);
+    }
+
+    #[test]
+    fn corrects_its_mistakes() {
+        let mut rng = rand::thread_rng();
+        let mut bot = bot(60, 1.0);
+        let text = words(&["ox"]);
+        let start = Instant::now();
+        bot.start(start);
+        let mut mistakes = 0;
+        let mut now = start + REACTION_TIME;
+        while let Some(state) = bot.tick(&text, now, &mut rng) {
+            mistakes += state.did_make_mistake as u32;
+            now += Duration::from_millis(200);
+        }
+        assert_eq!(bot.state.current_word, 1);
+        assert_eq!(mistakes, 2);
+        assert_eq!(bot.state.mistakes.uncorrected, 2);
+        assert_eq!(bot.state.mistakes.position, None);
+    }
+}
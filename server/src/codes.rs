@@ -0,0 +1,192 @@
+//! Lobby codes. Generated codes are always upper case, and JOIN upper cases what it's given, so
+//! players can type them in any case.
+use crate::config::{CodeStyle, LobbyConfig};
+use anyhow::{ensure, Result};
+use protocol::LobbyCode;
+use rand::{seq::SliceRandom, Rng};
+
+/// Short words that are easy to say aloud, for `CodeStyle::Words`.
+const CODE_WORDS: &str = include_str!("../code_words.txt");
+/// Generated codes never contain any of these.
+pub const BLOCKLIST: &str = include_str!("../code_blocklist.txt");
+/// Longest word in `CODE_WORDS`, so the config can check word codes fit in a `LobbyCode`.
+pub const MAX_CODE_WORD_LENGTH: usize = 5;
+/// Fewest codes the settings may leave us with, so finding a free one never takes long.
+pub const MIN_CODES: f64 = 10_000.0;
+/// Blocked or taken codes `generate` draws before giving up.
+const MAX_ATTEMPTS: usize = 100;
+
+pub struct CodeGenerator {
+    style: CodeStyle,
+    length: usize,
+    alphabet: Vec<u8>,
+    words: Vec<&'static str>,
+    word_count: usize,
+    /// Upper case, like the codes.
+    blocklist: Vec<String>,
+}
+
+impl CodeGenerator {
+    /// `blocklist` is newline separated, like the built in `BLOCKLIST`. Fails if it blocks so much
+    /// that there are fewer than `MIN_CODES` codes left.
+    pub fn new(config: &LobbyConfig, blocklist: &str) -> Result<Self> {
+        let codes = CodeGenerator {
+            style: config.code_style,
+            length: config.code_length,
+            alphabet: config.code_alphabet.as_bytes().to_vec(),
+            words: CODE_WORDS.lines().collect(),
+            word_count: config.code_words,
+            blocklist: blocklist
+                .lines()
+                .map(str::trim)
+                .filter(|word| !word.is_empty())
+                .map(str::to_ascii_uppercase)
+                .collect(),
+        };
+        let count = codes.count();
+        ensure!(
+            count >= MIN_CODES,
+            "The code blocklist leaves only {} possible lobby codes, at least {} are needed",
+            count,
+            MIN_CODES
+        );
+        Ok(codes)
+    }
+
+    /// How many codes there are, roughly. Only counts what a blocked word rules out on its own,
+    /// like a blocked letter or a code word containing a blocked word.
+    fn count(&self) -> f64 {
+        let (choices, picks) = match self.style {
+            CodeStyle::Letters => (
+                self.alphabet
+                    .iter()
+                    .filter(|&&c| !self.is_blocked(&(c as char).to_string()))
+                    .count(),
+                self.length,
+            ),
+            CodeStyle::Words => (
+                self.words
+                    .iter()
+                    .filter(|word| !self.is_blocked(&word.to_ascii_uppercase()))
+                    .count(),
+                self.word_count,
+            ),
+        };
+        (choices as f64).powi(picks as i32)
+    }
+
+    /// How many words `CodeStyle::Words` codes are made from.
+    pub fn word_choices() -> usize {
+        CODE_WORDS.lines().count()
+    }
+
+    fn is_blocked(&self, code: &str) -> bool {
+        self.blocklist
+            .iter()
+            .any(|word| code.contains(word.as_str()))
+    }
+
+    /// Picks codes until one is neither blocked nor `taken`, giving up after `MAX_ATTEMPTS`.
+    pub fn generate(
+        &self,
+        rng: &mut impl Rng,
+        taken: impl Fn(&LobbyCode) -> bool,
+    ) -> Option<LobbyCode> {
+        for _ in 0..MAX_ATTEMPTS {
+            let code = match self.style {
+                // Picking each character from the alphabet, rather than mapping random bytes onto
+                // it, gives every character the same chance.
+                CodeStyle::Letters => (0..self.length)
+                    .map(|_| *self.alphabet.choose(rng).expect("Validated alphabet") as char)
+                    .collect::<String>(),
+                CodeStyle::Words => (0..self.word_count)
+                    .map(|_| {
+                        self.words
+                            .choose(rng)
+                            .expect("Built in words")
+                            .to_ascii_uppercase()
+                    })
+                    .collect(),
+            };
+            if self.is_blocked(&code) {
+                continue;
+            }
+            let code = LobbyCode::new(code.as_bytes()).expect("Generated codes are always valid");
+            if !taken(&code) {
+                return Some(code);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator(style: CodeStyle, alphabet: &str, blocklist: &str) -> CodeGenerator {
+        let config = LobbyConfig {
+            code_style: style,
+            code_alphabet: alphabet.to_owned(),
+            ..LobbyConfig::default()
+        };
+        CodeGenerator::new(&config, blocklist).unwrap()
+    }
+
+    fn generate(codes: &CodeGenerator) -> LobbyCode {
+        codes.generate(&mut rand::thread_rng(), |_| false).unwrap()
+    }
+
+    #[test]
+    fn uses_the_alphabet() {
+        let codes = generator(CodeStyle::Letters, "0123456789", "");
+        for _ in 0..100 {
+            let code = generate(&codes);
+            assert_eq!(code.as_str().len(), 5);
+            assert!(code.as_str().bytes().all(|c| c.is_ascii_digit()));
+        }
+    }
+
+    #[test]
+    fn skips_blocked_codes() {
+        let codes = generator(CodeStyle::Letters, "ABCDEFGHIJKLMNOPQRSTUVWXYZ", "\nq\n");
+        for _ in 0..100 {
+            assert!(!generate(&codes).as_str().contains('Q'));
+        }
+    }
+
+    #[test]
+    fn refuses_blocklists_that_leave_too_few_codes() {
+        let config = LobbyConfig {
+            code_alphabet: "ABCDEFG".to_owned(),
+            ..LobbyConfig::default()
+        };
+        assert!(CodeGenerator::new(&config, "").is_ok());
+        assert!(CodeGenerator::new(&config, "A\nB\nC").is_err());
+    }
+
+    #[test]
+    fn gives_up_when_every_code_is_taken() {
+        let codes = generator(CodeStyle::Letters, "ABCDEFGHIJKLMNOPQRSTUVWXYZ", "");
+        assert_eq!(codes.generate(&mut rand::thread_rng(), |_| true), None);
+    }
+
+    #[test]
+    fn builds_codes_from_words() {
+        let codes = generator(CodeStyle::Words, "", BLOCKLIST);
+        let words: Vec<_> = CODE_WORDS.lines().map(str::to_ascii_uppercase).collect();
+        assert!(words.iter().all(|word| word.len() <= MAX_CODE_WORD_LENGTH));
+        for _ in 0..100 {
+            let code = generate(&codes);
+            let mut rest = code.as_str();
+            for _ in 0..3 {
+                let word = words
+                    .iter()
+                    .find(|word| rest.starts_with(word.as_str()))
+                    .unwrap_or_else(|| panic!("{} isn't made of words", code));
+                rest = &rest[word.len()..];
+            }
+            assert!(rest.is_empty(), "{}", code);
+        }
+    }
+}
@@ -0,0 +1,446 @@
+//! Server settings, read from an optional TOML file and overridden by command line flags.
+//!
+//! See `config.example.toml` for every setting and its default.
+use crate::codes::{CodeGenerator, MAX_CODE_WORD_LENGTH, MIN_CODES};
+use anyhow::{bail, ensure, Context, Result};
+use protocol::MAX_LOBBY_CODE_LENGTH;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub lobby: LobbyConfig,
+    pub limits: LimitsConfig,
+    pub corpora: CorporaConfig,
+    pub persistence: PersistenceConfig,
+    pub daily: DailyConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub address: String,
+    /// Port for plaintext clients.
+    pub port: u16,
+    /// Port for TLS clients, served alongside the plaintext one. Needs `tls_cert` and `tls_key`.
+    pub tls_port: Option<u16>,
+    /// PEM certificate chain for the TLS port.
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for the TLS port.
+    pub tls_key: Option<PathBuf>,
+    /// Unix socket to serve the admin interface on.
+    pub admin_socket: Option<PathBuf>,
+    /// Permissions for the admin socket. Anyone who can connect to it can run admin commands.
+    pub admin_socket_mode: u32,
+    /// Where to serve Prometheus metrics.
+    pub metrics_address: Option<SocketAddr>,
+    /// Seconds between heartbeat PINGs sent to each client.
+    pub ping_interval: u64,
+    /// Seconds to wait for a PONG before dropping the client.
+    pub ping_timeout: u64,
+    /// Seconds to let running races finish after SIGTERM/SIGINT before exiting.
+    pub shutdown_grace: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            address: "0.0.0.0".into(),
+            port: 1234,
+            tls_port: None,
+            tls_cert: None,
+            tls_key: None,
+            admin_socket: None,
+            admin_socket_mode: 0o600,
+            metrics_address: None,
+            ping_interval: 10,
+            ping_timeout: 30,
+            shutdown_grace: 30,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LobbyConfig {
+    pub code_style: CodeStyle,
+    /// Characters in `Letters` codes.
+    pub code_length: usize,
+    /// What `Letters` codes are made of. Upper case letters and digits only, since JOIN ignores
+    /// case.
+    pub code_alphabet: String,
+    /// How many words make up a `Words` code.
+    pub code_words: usize,
+    /// How many words each race text has.
+    pub word_count: usize,
+    /// Seconds between START and the race starting.
+    pub countdown: u64,
+    pub succession: SuccessionPolicy,
+    /// Seconds a lobby can wait for START before it's closed. 0 never closes it.
+    pub waiting_timeout: u64,
+    /// Seconds a race can go without anyone getting further before the lobby is closed. 0 never
+    /// closes it.
+    pub race_idle_timeout: u64,
+    /// Seconds a lobby stays open once its race is won and everyone has stopped typing, for the
+    /// leader to start another. 0 never closes it.
+    pub post_race_timeout: u64,
+    /// Seconds of warning players get before their lobby is closed for sitting idle.
+    pub expiry_warning: u64,
+    /// Seeds every random choice: lobby codes, texts, JOIN RANDOM, new leaders and bots. Random
+    /// when not set.
+    pub seed: Option<u64>,
+}
+
+impl Default for LobbyConfig {
+    fn default() -> Self {
+        LobbyConfig {
+            code_style: CodeStyle::Letters,
+            code_length: 5,
+            code_alphabet: "ABCDEFGHIJKLMNOPQRSTUVWXYZ".into(),
+            code_words: 3,
+            word_count: 20,
+            countdown: 5,
+            succession: SuccessionPolicy::Random,
+            waiting_timeout: 600,
+            race_idle_timeout: 120,
+            post_race_timeout: 60,
+            expiry_warning: 10,
+            seed: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// STATE updates a client may send per second, on average.
+    pub state_rate: f64,
+    pub state_burst: f64,
+    /// CHAT messages a client may send per second, on average.
+    pub chat_rate: f64,
+    pub chat_burst: f64,
+    /// Any other commands a client may send per second, on average.
+    pub command_rate: f64,
+    pub command_burst: f64,
+    /// Disconnect clients after they've been throttled this many times.
+    pub max_violations: u32,
+    /// Seconds it takes to forget one violation.
+    pub violation_decay: u64,
+    /// Longest command line, in bytes, a client may send.
+    pub max_line_length: usize,
+    /// Most bytes we'll read from a client before processing them.
+    pub max_buffer: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            state_rate: 30.0,
+            state_burst: 60.0,
+            chat_rate: 1.0,
+            chat_burst: 5.0,
+            command_rate: 5.0,
+            command_burst: 20.0,
+            max_violations: 10,
+            violation_decay: 30,
+            max_line_length: 2048,
+            max_buffer: 65536,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorporaConfig {
+    /// Newline separated word list to build race texts from, instead of the built in one.
+    pub words: Option<PathBuf>,
+    /// Newline separated words lobby codes must not contain, instead of the built in list.
+    pub code_blocklist: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceConfig {
+    /// Directory for anything that should outlive the server process. Created if missing.
+    pub data_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DailyConfig {
+    /// Mixed with the date to pick each day's text. Changing it changes today's text too.
+    pub seed: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Copy, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of every enclosing span.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            _ => bail!("Invalid log format: {}", s),
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeStyle {
+    /// `code_length` characters from `code_alphabet`, like "QXWTB".
+    Letters,
+    /// `code_words` short words run together, like "TIGERMAPLEOCEAN", easy to read out.
+    Words,
+}
+
+/// How to pick a new leader when the current one leaves the lobby.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SuccessionPolicy {
+    /// The player who has been in the lobby the longest.
+    Oldest,
+    Random,
+    /// The quickest finisher of the last race, or the oldest member if nobody left finished it.
+    Fastest,
+}
+
+impl FromStr for SuccessionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "oldest" => SuccessionPolicy::Oldest,
+            "random" => SuccessionPolicy::Random,
+            "fastest" => SuccessionPolicy::Fastest,
+            _ => bail!("Invalid succession policy: {}", s),
+        })
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Checks the settings make sense together. Errors name the offending setting the same way
+    /// the config file does.
+    pub fn validate(&self) -> Result<()> {
+        let network = &self.network;
+        ensure!(
+            network.ping_interval > 0,
+            "network.ping_interval must be at least 1"
+        );
+        ensure!(
+            network.ping_timeout > 0,
+            "network.ping_timeout must be at least 1"
+        );
+        match (network.tls_port, &network.tls_cert, &network.tls_key) {
+            (Some(_), Some(_), Some(_)) | (None, None, None) => (),
+            (Some(_), _, _) => bail!("network.tls_port needs network.tls_cert and network.tls_key"),
+            (None, _, _) => bail!("network.tls_cert and network.tls_key need network.tls_port"),
+        }
+        ensure!(
+            network.tls_port.is_none() || network.tls_port != Some(network.port),
+            "network.tls_port can't be the same as network.port"
+        );
+
+        let lobby = &self.lobby;
+        ensure!(
+            (1..=MAX_LOBBY_CODE_LENGTH).contains(&lobby.code_length),
+            "lobby.code_length must be between 1 and {}, got {}",
+            MAX_LOBBY_CODE_LENGTH,
+            lobby.code_length
+        );
+        let alphabet = lobby.code_alphabet.as_bytes();
+        ensure!(
+            alphabet
+                .iter()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()),
+            "lobby.code_alphabet can only have upper case letters and digits"
+        );
+        ensure!(
+            alphabet.len() >= 2,
+            "lobby.code_alphabet needs at least 2 characters"
+        );
+        ensure!(
+            alphabet
+                .iter()
+                .enumerate()
+                .all(|(i, c)| !alphabet[..i].contains(c)),
+            "lobby.code_alphabet has repeated characters"
+        );
+        let max_words = MAX_LOBBY_CODE_LENGTH / MAX_CODE_WORD_LENGTH;
+        ensure!(
+            (1..=max_words).contains(&lobby.code_words),
+            "lobby.code_words must be between 1 and {}, got {}",
+            max_words,
+            lobby.code_words
+        );
+        let (choices, picks, setting) = match lobby.code_style {
+            CodeStyle::Letters => (
+                alphabet.len(),
+                lobby.code_length,
+                "code_length and code_alphabet",
+            ),
+            CodeStyle::Words => (
+                CodeGenerator::word_choices(),
+                lobby.code_words,
+                "code_words",
+            ),
+        };
+        let codes = (choices as f64).powi(picks as i32);
+        ensure!(
+            codes >= MIN_CODES,
+            "lobby.{} only give {} possible codes, at least {} are needed",
+            setting,
+            codes,
+            MIN_CODES
+        );
+        ensure!(lobby.word_count > 0, "lobby.word_count must be at least 1");
+
+        let limits = &self.limits;
+        for &(name, rate, burst) in &[
+            ("state", limits.state_rate, limits.state_burst),
+            ("chat", limits.chat_rate, limits.chat_burst),
+            ("command", limits.command_rate, limits.command_burst),
+        ] {
+            ensure!(
+                rate > 0.0,
+                "limits.{}_rate must be positive, got {}",
+                name,
+                rate
+            );
+            ensure!(
+                burst >= 1.0,
+                "limits.{}_burst must be at least 1, got {}",
+                name,
+                burst
+            );
+        }
+        ensure!(
+            limits.max_line_length <= limits.max_buffer,
+            "limits.max_line_length ({}) can't be larger than limits.max_buffer ({})",
+            limits.max_line_length,
+            limits.max_buffer
+        );
+        Ok(())
+    }
+
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.network.ping_interval)
+    }
+
+    pub fn ping_timeout(&self) -> Duration {
+        Duration::from_secs(self.network.ping_timeout)
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.network.shutdown_grace)
+    }
+
+    pub fn violation_decay(&self) -> Duration {
+        Duration::from_secs(self.limits.violation_decay)
+    }
+
+    pub fn countdown(&self) -> Duration {
+        Duration::from_secs(self.lobby.countdown)
+    }
+
+    pub fn waiting_timeout(&self) -> Option<Duration> {
+        timeout(self.lobby.waiting_timeout)
+    }
+
+    pub fn race_idle_timeout(&self) -> Option<Duration> {
+        timeout(self.lobby.race_idle_timeout)
+    }
+
+    pub fn post_race_timeout(&self) -> Option<Duration> {
+        timeout(self.lobby.post_race_timeout)
+    }
+
+    pub fn expiry_warning(&self) -> Duration {
+        Duration::from_secs(self.lobby.expiry_warning)
+    }
+}
+
+/// Timeouts of 0 are turned off.
+fn timeout(seconds: u64) -> Option<Duration> {
+    match seconds {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Result<Config> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    /// The error `validate` gives for `toml`.
+    fn invalid(toml: &str) -> String {
+        let config = parse(toml).unwrap();
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let config = parse(include_str!("../config.example.toml")).unwrap();
+        config.validate().unwrap();
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(parse("[network]\nprot = 1234").is_err());
+        assert!(parse("[netwrok]\nport = 1234").is_err());
+        assert!(parse("[limits]\nstate_rate = \"fast\"").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_rates() {
+        assert!(invalid("[limits]\nchat_rate = 0.0").contains("limits.chat_rate"));
+        assert!(invalid("[limits]\nstate_burst = 0.5").contains("limits.state_burst"));
+        assert!(invalid("[limits]\nmax_line_length = 100\nmax_buffer = 10")
+            .contains("limits.max_line_length"));
+        assert!(invalid("[network]\nping_timeout = 0").contains("network.ping_timeout"));
+    }
+
+    #[test]
+    fn rejects_bad_code_settings() {
+        assert!(invalid("[lobby]\ncode_length = 0").contains("lobby.code_length"));
+        assert!(invalid("[lobby]\ncode_alphabet = \"abcde\"").contains("lobby.code_alphabet"));
+        assert!(invalid("[lobby]\ncode_alphabet = \"ABCA\"").contains("repeated"));
+        assert!(
+            invalid("[lobby]\ncode_alphabet = \"AB\"\ncode_length = 5").contains("possible codes")
+        );
+        assert!(
+            invalid("[lobby]\ncode_style = \"words\"\ncode_words = 1").contains("lobby.code_words")
+        );
+    }
+}
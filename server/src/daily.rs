@@ -0,0 +1,199 @@
+//! The daily challenge: one text per UTC day that everyone races, with a leaderboard.
+//!
+//! The text comes from `daily.seed` and the date, so it's the same all day and across restarts as
+//! long as neither the seed nor the corpus changes. Only a player's first race of the day counts,
+//! anything after is practice. Which race is someone's first goes by their address, so
+//! reconnecting or renaming doesn't buy another try, though players sharing an address share one.
+//! The board itself shows names. With `persistence.data_dir` set, the addresses are saved in the
+//! day's board file along with it, so treat that directory as holding personal data.
+use anyhow::{Context, Result};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+/// Days since the epoch, in UTC.
+pub type Day = u64;
+
+pub fn today() -> Day {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() / (24 * 60 * 60))
+}
+
+/// `day` as YYYY-MM-DD.
+pub fn date(day: Day) -> String {
+    // Howard Hinnant's civil_from_days, for days on or after the epoch.
+    let z = day + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let d = day_of_year - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = year_of_era + era * 400 + (m <= 2) as u64;
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finish {
+    pub player: String,
+    pub time_ms: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Board {
+    /// Addresses that have started the day's race, finished or not.
+    #[serde(default)]
+    entered_from: BTreeSet<IpAddr>,
+    /// Fastest first.
+    finishes: Vec<Finish>,
+}
+
+pub struct Daily {
+    seed: u64,
+    /// Where boards are saved, one JSON file per day.
+    dir: Option<PathBuf>,
+    boards: HashMap<Day, Board>,
+}
+
+impl Daily {
+    /// Keeps boards in `daily/` under `data_dir`, so they outlive restarts.
+    pub fn open(seed: u64, data_dir: Option<&Path>) -> Result<Self> {
+        let dir = data_dir.map(|data_dir| data_dir.join("daily"));
+        if let Some(dir) = &dir {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        Ok(Daily {
+            seed,
+            dir,
+            boards: HashMap::new(),
+        })
+    }
+
+    /// The day's text, `count` words from `corpus`.
+    pub fn words(&self, day: Day, corpus: &[String], count: usize) -> Vec<String> {
+        // Spread consecutive days out so their seeds don't share most of their bits.
+        let mut rng = StdRng::seed_from_u64(self.seed ^ day.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        corpus.choose_multiple(&mut rng, count).cloned().collect()
+    }
+
+    fn path(&self, day: Day) -> Option<PathBuf> {
+        Some(self.dir.as_ref()?.join(format!("{}.json", date(day))))
+    }
+
+    fn board(&mut self, day: Day) -> &mut Board {
+        // Lobbies can still be racing yesterday's text just after midnight.
+        self.boards.retain(|&board_day, _| board_day + 1 >= day);
+        let path = self.path(day);
+        self.boards.entry(day).or_insert_with(|| {
+            let path = match path {
+                Some(path) if path.exists() => path,
+                _ => return Board::default(),
+            };
+            std::fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|contents| Ok(serde_json::from_str(&contents)?))
+                .unwrap_or_else(|err| {
+                    error!(path = %path.display(), err = ?err, "Failed to load daily board");
+                    Board::default()
+                })
+        })
+    }
+
+    fn save(&self, day: Day) {
+        if let (Some(path), Some(board)) = (self.path(day), self.boards.get(&day)) {
+            let contents = serde_json::to_string(board).expect("Boards always serialize");
+            if let Err(err) = std::fs::write(&path, contents) {
+                error!(path = %path.display(), %err, "Failed to save daily board");
+            }
+        }
+    }
+
+    /// Signs a player at `address` up for the day's race, returning whether it's the first try
+    /// from there and counts.
+    pub fn enter(&mut self, day: Day, address: IpAddr) -> bool {
+        let first = self.board(day).entered_from.insert(address);
+        if first {
+            self.save(day);
+        }
+        first
+    }
+
+    /// Puts a counted race on the board, returning where it placed.
+    pub fn finish(&mut self, day: Day, player: &str, time: Duration) -> usize {
+        let finish = Finish {
+            player: player.to_owned(),
+            time_ms: time.as_millis() as u64,
+        };
+        let finishes = &mut self.board(day).finishes;
+        let place = finishes.partition_point(|other| other.time_ms <= finish.time_ms);
+        finishes.insert(place, finish);
+        self.save(day);
+        place + 1
+    }
+
+    /// The day's finishes, fastest first.
+    pub fn rankings(&mut self, day: Day) -> &[Finish] {
+        &self.board(day).finishes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corpus() -> Vec<String> {
+        (0..100).map(|i| format!("word{}", i)).collect()
+    }
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(11_016), "2000-02-29");
+        assert_eq!(date(20_744), "2026-10-18");
+    }
+
+    #[test]
+    fn same_text_all_day() {
+        let daily = Daily::open(7, None).unwrap();
+        let corpus = corpus();
+        assert_eq!(
+            daily.words(20_744, &corpus, 5),
+            Daily::open(7, None).unwrap().words(20_744, &corpus, 5)
+        );
+        assert_ne!(
+            daily.words(20_744, &corpus, 5),
+            daily.words(20_745, &corpus, 5)
+        );
+        assert_ne!(
+            daily.words(20_744, &corpus, 5),
+            Daily::open(8, None).unwrap().words(20_744, &corpus, 5)
+        );
+    }
+
+    #[test]
+    fn only_the_first_race_counts() {
+        let mut daily = Daily::open(0, None).unwrap();
+        let ashkan = IpAddr::from([10, 0, 0, 1]);
+        assert!(daily.enter(1, ashkan));
+        assert!(!daily.enter(1, ashkan));
+        assert!(daily.enter(2, ashkan));
+        assert!(daily.enter(1, IpAddr::from([10, 0, 0, 2])));
+        assert_eq!(daily.finish(1, "ashkan", Duration::from_secs(20)), 1);
+        assert_eq!(daily.finish(1, "norcalli", Duration::from_secs(10)), 1);
+        let players: Vec<_> = daily
+            .rankings(1)
+            .iter()
+            .map(|finish| finish.player.as_str())
+            .collect();
+        assert_eq!(players, ["norcalli", "ashkan"]);
+        assert!(daily.rankings(2).is_empty());
+    }
+}
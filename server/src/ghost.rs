@@ -0,0 +1,320 @@
+//! Recorded races, so players can race a ghost of their best run on a text.
+//!
+//! Every STATE a player sends during a race is kept with how long after the start it arrived.
+//! Finishing a text faster than before makes that timeline the player's best, which a `Replay`
+//! plays back in real time.
+//!
+//! With `persistence.data_dir` set, every new best is also appended to `ghosts.jsonl` there and
+//! read back at startup. Like recordings, saved timelines only keep where the player was and
+//! whether they were making a mistake.
+use anyhow::{Context, Result};
+use protocol::PlayerState;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+/// STATE updates with their offset from the start of the race.
+pub type Timeline = Vec<(Duration, PlayerState)>;
+
+#[derive(Clone)]
+pub struct Run {
+    pub player: String,
+    pub time: Duration,
+    pub timeline: Arc<Timeline>,
+}
+
+/// A run as saved on disk, one per line. States are `[at_ms, word, character, mistake]`.
+#[derive(Serialize, Deserialize)]
+struct SavedRun {
+    words: Vec<String>,
+    player: String,
+    time_ms: u64,
+    timeline: Vec<(u64, usize, usize, bool)>,
+}
+
+impl SavedRun {
+    fn new(words: &[String], run: &Run) -> Self {
+        SavedRun {
+            words: words.to_vec(),
+            player: run.player.clone(),
+            time_ms: run.time.as_millis() as u64,
+            timeline: run
+                .timeline
+                .iter()
+                .map(|(at, state)| {
+                    (
+                        at.as_millis() as u64,
+                        state.current_word,
+                        state.current_completed_character,
+                        state.did_make_mistake,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn run(self) -> (Vec<String>, Run) {
+        let timeline = self
+            .timeline
+            .into_iter()
+            .map(|(at_ms, word, character, mistake)| {
+                let state = PlayerState {
+                    current_word: word,
+                    current_completed_character: character,
+                    did_make_mistake: mistake,
+                    ..PlayerState::default()
+                };
+                (Duration::from_millis(at_ms), state)
+            })
+            .collect();
+        let run = Run {
+            player: self.player,
+            time: Duration::from_millis(self.time_ms),
+            timeline: Arc::new(timeline),
+        };
+        (self.words, run)
+    }
+}
+
+/// The best run of every player on every text.
+#[derive(Default)]
+pub struct Records {
+    /// Where new bests are appended, one JSON object per line.
+    path: Option<PathBuf>,
+    texts: HashMap<String, HashMap<String, Run>>,
+}
+
+impl Records {
+    /// Keeps bests in `ghosts.jsonl` under `data_dir`, so they outlive restarts.
+    pub fn open(data_dir: Option<&Path>) -> Result<Self> {
+        let mut records = Records::default();
+        let data_dir = match data_dir {
+            Some(data_dir) => data_dir,
+            None => return Ok(records),
+        };
+        std::fs::create_dir_all(data_dir)
+            .with_context(|| format!("Failed to create {}", data_dir.display()))?;
+        let path = data_dir.join("ghosts.jsonl");
+        if path.exists() {
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let mut saved = 0;
+            for line in contents.lines() {
+                match serde_json::from_str::<SavedRun>(line) {
+                    Ok(run) => {
+                        let (words, run) = run.run();
+                        records.insert(&words, run);
+                        saved += 1;
+                    }
+                    // Most likely the server died halfway through appending it.
+                    Err(err) => error!(path = %path.display(), %err, "Skipping invalid ghost"),
+                }
+            }
+            // Every best that was later beaten is still in there.
+            let runs = records.texts.values().map(HashMap::len).sum::<usize>();
+            if saved > runs {
+                records.compact(&path)?;
+            }
+        }
+        records.path = Some(path);
+        Ok(records)
+    }
+
+    fn key(words: &[String]) -> String {
+        words.join(" ")
+    }
+
+    /// Rewrites `path` with only the runs that are still bests.
+    fn compact(&self, path: &Path) -> Result<()> {
+        let mut contents = String::new();
+        for (text, best) in &self.texts {
+            let words: Vec<String> = text.split(' ').map(str::to_owned).collect();
+            for run in best.values() {
+                contents += &serde_json::to_string(&SavedRun::new(&words, run))?;
+                contents.push('\n');
+            }
+        }
+        let temporary = path.with_extension("jsonl.tmp");
+        std::fs::write(&temporary, contents)
+            .with_context(|| format!("Failed to write {}", temporary.display()))?;
+        std::fs::rename(&temporary, path)
+            .with_context(|| format!("Failed to replace {}", path.display()))
+    }
+
+    /// Keeps `run` if it's the player's best on this text, returning whether it was.
+    fn insert(&mut self, words: &[String], run: Run) -> bool {
+        let best = self.texts.entry(Self::key(words)).or_default();
+        match best.get(&run.player) {
+            Some(previous) if previous.time <= run.time => false,
+            _ => {
+                best.insert(run.player.clone(), run);
+                true
+            }
+        }
+    }
+
+    /// Keeps `run` if it's the player's best on this text, and saves it, returning whether it was.
+    pub fn record(&mut self, words: &[String], run: Run) -> bool {
+        let saved = self.path.as_ref().map(|_| SavedRun::new(words, &run));
+        if !self.insert(words, run) {
+            return false;
+        }
+        if let (Some(path), Some(saved)) = (&self.path, saved) {
+            let mut line = serde_json::to_string(&saved).expect("Runs always serialize");
+            line.push('\n');
+            let saved = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(line.as_bytes()));
+            if let Err(err) = saved {
+                error!(path = %path.display(), %err, "Failed to save ghost");
+            }
+        }
+        true
+    }
+
+    pub fn personal_best(&self, words: &[String], player: &str) -> Option<&Run> {
+        self.texts.get(&Self::key(words))?.get(player)
+    }
+
+    /// The fastest run anyone has on this text.
+    pub fn record_holder(&self, words: &[String]) -> Option<&Run> {
+        self.texts
+            .get(&Self::key(words))?
+            .values()
+            .min_by_key(|run| run.time)
+    }
+}
+
+/// Plays a recorded timeline back against a new race start.
+pub struct Replay {
+    timeline: Arc<Timeline>,
+    /// The next update to play.
+    next: usize,
+    started: Option<Instant>,
+    state: PlayerState,
+}
+
+impl Replay {
+    pub fn new(run: &Run) -> Self {
+        Replay {
+            timeline: Arc::clone(&run.timeline),
+            next: 0,
+            started: None,
+            state: PlayerState::default(),
+        }
+    }
+
+    pub fn state(&self) -> PlayerState {
+        self.state
+    }
+
+    pub fn start(&mut self, now: Instant) {
+        self.reset();
+        self.started = Some(now);
+    }
+
+    pub fn reset(&mut self) {
+        self.next = 0;
+        self.started = None;
+        self.state = PlayerState::default();
+    }
+
+    /// Plays every update that's due by `now`, returning the latest if there were any.
+    pub fn tick(&mut self, now: Instant) -> Option<PlayerState> {
+        let elapsed = now.saturating_duration_since(self.started?);
+        let due = self.timeline[self.next..]
+            .iter()
+            .take_while(|&&(offset, _)| offset <= elapsed)
+            .count();
+        if due == 0 {
+            return None;
+        }
+        self.next += due;
+        self.state = self.timeline[self.next - 1].1;
+        Some(self.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(word: usize) -> PlayerState {
+        PlayerState {
+            current_word: word,
+            ..PlayerState::default()
+        }
+    }
+
+    fn run(player: &str, seconds: u64) -> Run {
+        Run {
+            player: player.to_owned(),
+            time: Duration::from_secs(seconds),
+            timeline: Arc::new(vec![
+                (Duration::from_secs(1), at(1)),
+                (Duration::from_secs(2), at(2)),
+                (Duration::from_secs(seconds), at(3)),
+            ]),
+        }
+    }
+
+    #[test]
+    fn keeps_only_personal_bests() {
+        let text = vec!["to".to_owned(), "be".to_owned(), "or".to_owned()];
+        let other = vec!["not".to_owned()];
+        let mut records = Records::default();
+        assert!(records.record(&text, run("ashkan", 10)));
+        assert!(!records.record(&text, run("ashkan", 12)));
+        assert!(records.record(&text, run("ashkan", 8)));
+        assert!(records.record(&text, run("norcalli", 9)));
+        assert_eq!(
+            records.personal_best(&text, "ashkan").unwrap().time,
+            Duration::from_secs(8)
+        );
+        assert_eq!(records.record_holder(&text).unwrap().player, "ashkan");
+        assert!(records.personal_best(&other, "ashkan").is_none());
+        assert!(records.record_holder(&other).is_none());
+    }
+
+    #[test]
+    fn bests_outlive_restarts() {
+        let dir = std::env::temp_dir().join(format!("typeracer-ghosts-{}", std::process::id()));
+        let text = vec!["to".to_owned(), "be".to_owned()];
+        let mut records = Records::open(Some(&dir)).unwrap();
+        records.record(&text, run("ashkan", 10));
+        records.record(&text, run("ashkan", 12));
+        records.record(&text, run("ashkan", 8));
+        records.record(&text, run("norcalli", 9));
+
+        let records = Records::open(Some(&dir)).unwrap();
+        let best = records.personal_best(&text, "ashkan").unwrap();
+        assert_eq!(best.time, Duration::from_secs(8));
+        assert_eq!(best.timeline, run("ashkan", 8).timeline);
+        assert_eq!(records.record_holder(&text).unwrap().player, "ashkan");
+        // The beaten best is dropped from the file.
+        let saved = std::fs::read_to_string(dir.join("ghosts.jsonl")).unwrap();
+        assert_eq!(saved.lines().count(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replays_in_real_time() {
+        let mut replay = Replay::new(&run("ashkan", 5));
+        let start = Instant::now();
+        assert_eq!(replay.tick(start), None);
+        replay.start(start);
+        assert_eq!(replay.tick(start), None);
+        assert_eq!(replay.tick(start + Duration::from_secs(1)), Some(at(1)));
+        assert_eq!(replay.tick(start + Duration::from_secs(1)), None);
+        // Catches up on anything it missed.
+        assert_eq!(replay.tick(start + Duration::from_secs(6)), Some(at(3)));
+        assert_eq!(replay.tick(start + Duration::from_secs(7)), None);
+    }
+}
@@ -0,0 +1,2213 @@
+//! The typeracer server. `main.rs` only parses the command line, everything else lives here so
+//! tests can run a `Server` in process.
+mod accuracy;
+pub mod admin;
+mod bot;
+mod codes;
+pub mod config;
+mod daily;
+mod ghost;
+mod lifecycle;
+mod metrics;
+mod rate_limit;
+mod recording;
+mod stats;
+mod tls;
+
+use accuracy::Accuracy;
+use admin::{AdminCommand, AdminConnection};
+use anyhow::{Context, Result};
+use bot::Bot;
+use codes::CodeGenerator;
+use config::{Config, SuccessionPolicy};
+use daily::{Daily, Day};
+use ghost::{Records, Run, Timeline};
+use lifecycle::{Admission, LobbyState, Transition};
+use metrics::{Metrics, MetricsServer};
+use protocol::{drain_lines, ClientId, ClientMessage, LobbyCode, PlayerState, ServerMessage};
+use rand::{
+    rngs::StdRng,
+    seq::{IteratorRandom, SliceRandom},
+    Rng, SeedableRng,
+};
+use rate_limit::{CommandClass, RateLimit, RateLimiter, RateLimits, Verdict};
+use recording::{Event, Playback, RaceId, Recording, Recordings};
+use stats::Stats;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::io::{self, prelude::*};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tls::Stream;
+use tracing::{debug, error, info, info_span, trace, warn};
+
+/// How many recent chat lines a lobby keeps around for new joiners.
+const CHAT_BACKLOG: usize = 20;
+
+struct Lobby {
+    code: LobbyCode,
+    leader_id: ClientId,
+    state: LobbyState,
+    winner: Option<ClientId>,
+    /// Ordered so every member hears about the others in the same order.
+    clients: BTreeSet<ClientId>,
+    /// Members without a connection, typing on the server. Also in `clients`.
+    bots: BTreeMap<ClientId, Bot>,
+    /// When each member joined, for picking the oldest member as the next leader.
+    joined_at: HashMap<ClientId, Instant>,
+    /// Addresses the leader has banned from joining again, for as long as the lobby is open. There
+    /// are no accounts, so this keeps out everyone sharing the address too, like a household behind
+    /// one NAT.
+    banned: HashSet<IpAddr>,
+    // TODO(ashkan): make reference?
+    words: Vec<String>,
+    /// Recent CHAT lines, ready to send as is.
+    chat: VecDeque<String>,
+    race_started: Option<Instant>,
+    /// Everyone who typed the whole text, in order, with how long it took them.
+    finishers: Vec<(ClientId, Duration)>,
+    /// Every STATE each player sent during the current race, to make ghosts from.
+    timelines: HashMap<ClientId, Timeline>,
+    /// Where each player has gone wrong in the current race.
+    accuracy: HashMap<ClientId, Accuracy>,
+    /// The latest race, from its countdown until the next one starts or the lobby closes.
+    recording: Option<Recording>,
+    /// Set when the text is the daily challenge for this day.
+    daily: Option<Day>,
+    /// Players whose finish in this race goes on the daily board.
+    daily_entrants: HashSet<ClientId>,
+    /// The last time anything happened that should keep the lobby open.
+    last_activity: Instant,
+    /// Whether the members have been told the lobby is about to expire.
+    expiry_warned: bool,
+}
+
+impl Lobby {
+    /// A lobby waiting for its leader, the only member, to start racing `words`.
+    fn new(code: LobbyCode, leader_id: ClientId, words: Vec<String>, daily: Option<Day>) -> Self {
+        Lobby {
+            winner: None,
+            leader_id,
+            code,
+            state: LobbyState::WaitingForStart,
+            clients: [leader_id].iter().copied().collect(),
+            bots: BTreeMap::new(),
+            joined_at: [(leader_id, Instant::now())].iter().copied().collect(),
+            banned: HashSet::new(),
+            words,
+            chat: VecDeque::new(),
+            race_started: None,
+            finishers: vec![],
+            timelines: HashMap::new(),
+            accuracy: HashMap::new(),
+            recording: None,
+            daily,
+            daily_entrants: HashSet::new(),
+            last_activity: Instant::now(),
+            expiry_warned: false,
+        }
+    }
+
+    /// Moves the lobby on, if `transition` makes sense in its current state.
+    fn transition(&mut self, transition: Transition) -> Result<()> {
+        self.state = self.state.next(transition)?;
+        Ok(())
+    }
+
+    /// Forgets a member who left or was kicked, apart from where they finished.
+    fn remove_member(&mut self, client_id: ClientId) {
+        self.clients.remove(&client_id);
+        self.joined_at.remove(&client_id);
+        self.timelines.remove(&client_id);
+        self.accuracy.remove(&client_id);
+        self.daily_entrants.remove(&client_id);
+    }
+
+    /// Whether anyone other than bots is left.
+    fn has_players(&self) -> bool {
+        self.clients.iter().any(|id| !self.bots.contains_key(id))
+    }
+
+    /// Keeps the lobby from expiring for a while longer.
+    fn touch(&mut self) {
+        self.last_activity = Instant::now();
+        self.expiry_warned = false;
+    }
+
+    /// How long the lobby can sit idle in its current state before it's closed.
+    fn idle_timeout(&self, config: &Config) -> Option<Duration> {
+        match self.state {
+            LobbyState::WaitingForStart => config.waiting_timeout(),
+            LobbyState::RaceRunning => config.race_idle_timeout(),
+            LobbyState::RaceFinished => config.post_race_timeout(),
+            LobbyState::Countdown(_) | LobbyState::Dead => None,
+        }
+    }
+
+    /// Characters in the text, counting the spaces between words.
+    fn text_length(&self) -> usize {
+        self.words.iter().map(|word| word.len()).sum::<usize>() + self.words.len().saturating_sub(1)
+    }
+}
+
+struct ClientState {
+    stream: Stream,
+    addr: SocketAddr,
+    read_buffer: Vec<u8>,
+    id: ClientId,
+    lobby: Option<LobbyCode>,
+    state: PlayerState,
+    heartbeat: Heartbeat,
+    name: Option<String>,
+    rate_limiter: RateLimiter,
+    /// The latest STATE the rate limiter dropped. STATEs say where the player is rather than what
+    /// they typed, so this one is sent on once there's room and the ones before it don't matter.
+    held_state: Option<PlayerState>,
+    /// A recorded race we're streaming to the client, which it asked for with REPLAY.
+    playback: Option<Playback>,
+    span: tracing::Span,
+}
+
+impl ClientState {
+    fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("P{}", self.id),
+        }
+    }
+}
+
+/// Tracks the PING/PONG exchange with a client so we can find half-open connections.
+struct Heartbeat {
+    next_nonce: u64,
+    last_sent: Instant,
+    /// The nonce and send time of the PING we're still waiting on a PONG for.
+    outstanding: Option<(u64, Instant)>,
+    /// Round trip time measured by the last answered PING.
+    rtt: Option<Duration>,
+}
+
+impl Heartbeat {
+    fn new() -> Self {
+        Heartbeat {
+            next_nonce: 0,
+            last_sent: Instant::now(),
+            outstanding: None,
+            rtt: None,
+        }
+    }
+}
+
+/// Everything that can happen to a client, queued up for the main loop.
+#[derive(Debug)]
+enum Command {
+    Client(ClientMessage),
+    Disconnect(DisconnectReason),
+    /// Tell the client's lobby where they are, like a STATE they didn't send.
+    ShareState,
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Client(message) => message.name(),
+            Command::Disconnect(_) => "DISCONNECT",
+            Command::ShareState => "SHARE_STATE",
+        }
+    }
+}
+
+/// Why we dropped a client, for the logs and metrics.
+#[derive(Debug, Copy, Clone)]
+enum DisconnectReason {
+    HungUp,
+    ReadError,
+    SocketError,
+    WriteError,
+    PingTimeout,
+    ProtocolError,
+    RateLimited,
+    Kicked,
+}
+
+impl DisconnectReason {
+    fn name(self) -> &'static str {
+        match self {
+            DisconnectReason::HungUp => "hung_up",
+            DisconnectReason::ReadError => "read_error",
+            DisconnectReason::SocketError => "socket_error",
+            DisconnectReason::WriteError => "write_error",
+            DisconnectReason::PingTimeout => "ping_timeout",
+            DisconnectReason::ProtocolError => "protocol_error",
+            DisconnectReason::RateLimited => "rate_limited",
+            DisconnectReason::Kicked => "kicked",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ParseAction {
+    CreateLobby {
+        leader_id: ClientId,
+        daily: bool,
+    },
+    StartLobby,
+    SendWords {
+        lobby_code: LobbyCode,
+        client_id: ClientId,
+    },
+    // NO OP = NO OPERATION
+    Noop,
+    UpdatedState {
+        lobby_code: LobbyCode,
+        client_id: ClientId,
+        new_state: PlayerState,
+    },
+    JoinLobby {
+        lobby_code: LobbyCode,
+        client_id: ClientId,
+    },
+    RestartLobby,
+    Disconnect {
+        client_id: ClientId,
+        reason: DisconnectReason,
+    },
+    KickPlayer {
+        lobby_code: LobbyCode,
+        client_id: ClientId,
+        ban: bool,
+    },
+    PromoteLeader {
+        lobby_code: LobbyCode,
+        client_id: ClientId,
+    },
+    Chat {
+        lobby_code: LobbyCode,
+        client_id: ClientId,
+        message: String,
+    },
+    Rename {
+        lobby_code: LobbyCode,
+        client_id: ClientId,
+    },
+    ReportLatency {
+        lobby_code: LobbyCode,
+        client_id: ClientId,
+        rtt: Duration,
+    },
+    AddBot {
+        lobby_code: LobbyCode,
+        wpm: u32,
+        variance: Option<u32>,
+    },
+    AddGhost {
+        lobby_code: LobbyCode,
+        record: bool,
+    },
+    Replay {
+        race: RaceId,
+        speed: u32,
+    },
+    StartDaily {
+        lobby_code: LobbyCode,
+    },
+    SendDailyBoard,
+    SendStats,
+    /// Tell the client the command makes no sense while the lobby is in `state`.
+    Invalid {
+        command: &'static str,
+        state: &'static str,
+    },
+}
+
+fn transition_client(
+    client: &mut ClientState,
+    lobby: Option<&Lobby>,
+    command: Command,
+) -> ParseAction {
+    let command = match command {
+        Command::Client(message) => message,
+        Command::Disconnect(reason) => {
+            return ParseAction::Disconnect {
+                client_id: client.id,
+                reason,
+            }
+        }
+        Command::ShareState => {
+            return match lobby {
+                Some(lobby) => ParseAction::UpdatedState {
+                    lobby_code: lobby.code,
+                    client_id: client.id,
+                    new_state: client.state,
+                },
+                None => ParseAction::Noop,
+            }
+        }
+    };
+    if let ClientMessage::Pong(nonce) = command {
+        return match client.heartbeat.outstanding {
+            Some((expected, sent_at)) if expected == nonce => {
+                let rtt = sent_at.elapsed();
+                client.heartbeat.outstanding = None;
+                client.heartbeat.rtt = Some(rtt);
+                match lobby {
+                    Some(lobby) => ParseAction::ReportLatency {
+                        lobby_code: lobby.code,
+                        client_id: client.id,
+                        rtt,
+                    },
+                    None => ParseAction::Noop,
+                }
+            }
+            _ => {
+                warn!(nonce, "Unexpected PONG");
+                ParseAction::Noop
+            }
+        };
+    }
+    if let ClientMessage::Name(name) = command {
+        client.name = Some(name);
+        return match lobby {
+            Some(lobby) => ParseAction::Rename {
+                lobby_code: lobby.code,
+                client_id: client.id,
+            },
+            None => ParseAction::Noop,
+        };
+    }
+    if let ClientMessage::DailyBoard = command {
+        return ParseAction::SendDailyBoard;
+    }
+    if let ClientMessage::Stats = command {
+        return ParseAction::SendStats;
+    }
+    if lobby.is_none() {
+        // ensure!(matches!(command, ClientMessage::Create), "Got a command other than CREATE with no lobby");
+        if let ClientMessage::Replay { race, speed } = command {
+            return ParseAction::Replay {
+                race,
+                speed: speed.unwrap_or(1),
+            };
+        }
+        // Joining a lobby stops any replay, the two would talk over each other.
+        client.playback = None;
+        return match command {
+            ClientMessage::Create => ParseAction::CreateLobby {
+                leader_id: client.id,
+                daily: false,
+            },
+            ClientMessage::Daily => ParseAction::CreateLobby {
+                leader_id: client.id,
+                daily: true,
+            },
+            ClientMessage::Join(code) => {
+                let code = code.to_ascii_uppercase();
+                client.lobby = Some(code);
+                client.state = PlayerState::default();
+                ParseAction::JoinLobby {
+                    lobby_code: code,
+                    client_id: client.id,
+                }
+            }
+            ClientMessage::JoinRandom => {
+                client.state = PlayerState::default();
+                ParseAction::JoinLobby {
+                    lobby_code: LobbyCode::EMPTY,
+                    client_id: client.id,
+                }
+            }
+            ClientMessage::Pong(_)
+            | ClientMessage::Name(_)
+            | ClientMessage::DailyBoard
+            | ClientMessage::Stats => unreachable!(),
+            _ => {
+                warn!(command = command.name(), "Command needs a lobby");
+                ParseAction::Invalid {
+                    command: command.name(),
+                    state: "NoLobby",
+                }
+            }
+        };
+    }
+    let lobby = lobby.unwrap();
+    match lobby.state.admit(&command) {
+        Admission::Accept => (),
+        Admission::Ignore => {
+            warn!(
+                command = command.name(),
+                state = lobby.state.name(),
+                "Ignoring command"
+            );
+            return ParseAction::Noop;
+        }
+        Admission::Reject => {
+            warn!(
+                command = command.name(),
+                state = lobby.state.name(),
+                "Rejecting command"
+            );
+            return ParseAction::Invalid {
+                command: command.name(),
+                state: lobby.state.name(),
+            };
+        }
+    }
+    match command {
+        ClientMessage::Daily => {
+            if client.id != lobby.leader_id {
+                warn!("Player is misbehaving :(");
+                return ParseAction::Noop;
+            }
+            ParseAction::StartDaily {
+                lobby_code: lobby.code,
+            }
+        }
+        ClientMessage::Start | ClientMessage::Restart => {
+            if client.id != lobby.leader_id {
+                warn!("Player is misbehaving :(");
+                return ParseAction::Noop;
+            }
+            match command {
+                ClientMessage::Start => ParseAction::StartLobby,
+                _ => ParseAction::RestartLobby,
+            }
+        }
+        ClientMessage::State(new_state) => {
+            // TODO(ashkan): should probably check no one is cheating by updating state more than
+            // one character at a time.
+            client.state = new_state;
+            ParseAction::UpdatedState {
+                lobby_code: lobby.code,
+                client_id: client.id,
+                new_state,
+            }
+        }
+        ClientMessage::Kick(target) | ClientMessage::Ban(target) => {
+            if client.id != lobby.leader_id {
+                warn!("Player is misbehaving :(");
+                return ParseAction::Noop;
+            }
+            if target == client.id || !lobby.clients.contains(&target) {
+                warn!(target, "Can't remove player from lobby");
+                return ParseAction::Noop;
+            }
+            ParseAction::KickPlayer {
+                lobby_code: lobby.code,
+                client_id: target,
+                ban: matches!(command, ClientMessage::Ban(_)),
+            }
+        }
+        ClientMessage::Promote(target) => {
+            if client.id != lobby.leader_id {
+                warn!("Player is misbehaving :(");
+                return ParseAction::Noop;
+            }
+            if target == client.id
+                || !lobby.clients.contains(&target)
+                || lobby.bots.contains_key(&target)
+            {
+                warn!(target, "Can't promote player");
+                return ParseAction::Noop;
+            }
+            ParseAction::PromoteLeader {
+                lobby_code: lobby.code,
+                client_id: target,
+            }
+        }
+        ClientMessage::Words => ParseAction::SendWords {
+            client_id: client.id,
+            lobby_code: lobby.code,
+        },
+        ClientMessage::Chat(message) => ParseAction::Chat {
+            lobby_code: lobby.code,
+            client_id: client.id,
+            message,
+        },
+        ClientMessage::AddBot { wpm, variance } => {
+            if client.id != lobby.leader_id {
+                warn!("Player is misbehaving :(");
+                return ParseAction::Noop;
+            }
+            if lobby.bots.len() >= bot::MAX_BOTS || wpm > bot::MAX_WPM {
+                warn!(wpm, bots = lobby.bots.len(), "Can't add bot");
+                return ParseAction::Noop;
+            }
+            ParseAction::AddBot {
+                lobby_code: lobby.code,
+                wpm,
+                variance,
+            }
+        }
+        // Anyone can race a ghost, it's their own run after all.
+        ClientMessage::AddGhost { record } => {
+            if lobby.bots.len() >= bot::MAX_BOTS {
+                warn!(bots = lobby.bots.len(), "Can't add ghost");
+                return ParseAction::Noop;
+            }
+            ParseAction::AddGhost {
+                lobby_code: lobby.code,
+                record,
+            }
+        }
+        // Turned away by `admit` or handled before we get here.
+        ClientMessage::Create
+        | ClientMessage::Join(_)
+        | ClientMessage::JoinRandom
+        | ClientMessage::Replay { .. }
+        | ClientMessage::Pong(_)
+        | ClientMessage::Name(_)
+        | ClientMessage::DailyBoard
+        | ClientMessage::Stats => unreachable!(),
+    }
+}
+
+const WORDS: &str = include_str!("../words.txt");
+
+/// Picks who should lead `lobby` next out of its remaining members.
+fn choose_successor(
+    lobby: &Lobby,
+    policy: SuccessionPolicy,
+    rng: &mut impl Rng,
+) -> Option<ClientId> {
+    let oldest = |id: &&ClientId| lobby.joined_at.get(id).copied();
+    // Bots can't lead.
+    let players = lobby
+        .clients
+        .iter()
+        .filter(|id| !lobby.bots.contains_key(id));
+    match policy {
+        SuccessionPolicy::Oldest => players.min_by_key(oldest).copied(),
+        SuccessionPolicy::Random => players.choose(rng).copied(),
+        // Finishers stay in finishing order until the next race starts, so this is still the last
+        // race after a RESTART. Anyone who didn't finish comes after them, oldest first.
+        SuccessionPolicy::Fastest => players
+            .min_by_key(|id| {
+                let place = lobby
+                    .finishers
+                    .iter()
+                    .position(|(finisher, _)| finisher == *id);
+                (place.is_none(), place, oldest(id))
+            })
+            .copied(),
+    }
+}
+
+/// Tells `lobby` where a player or bot is now, announcing the winner if they just finished.
+/// Returns how long the race took them if they did.
+fn record_state(
+    lobby: &mut Lobby,
+    client_id: ClientId,
+    new_state: PlayerState,
+    clients: &mut BTreeMap<ClientId, ClientState>,
+    metrics: &mut Metrics,
+    command_buffer: &mut VecDeque<(ClientId, Command)>,
+) -> Option<Duration> {
+    let mut finished = None;
+    let racing = matches!(
+        lobby.state,
+        LobbyState::RaceRunning | LobbyState::RaceFinished
+    );
+    if racing {
+        lobby.touch();
+        lobby
+            .accuracy
+            .entry(client_id)
+            .or_default()
+            .update(new_state, &lobby.words);
+    }
+    // check if this is finished.
+    if racing
+        && new_state.current_word >= lobby.words.len()
+        && !lobby.finishers.iter().any(|&(id, _)| id == client_id)
+    {
+        if let Some(race_started) = lobby.race_started {
+            let elapsed = race_started.elapsed();
+            finished = Some(elapsed);
+            lobby.finishers.push((client_id, elapsed));
+            if let Some(recording) = &mut lobby.recording {
+                recording.record(Event::Finished {
+                    client: client_id,
+                    place: lobby.finishers.len(),
+                    time_ms: elapsed.as_millis() as u64,
+                });
+            }
+            info!(
+                finisher = client_id,
+                place = lobby.finishers.len(),
+                accuracy = lobby.accuracy[&client_id].percent(lobby.text_length()),
+                "Finished the race"
+            );
+            let minutes = elapsed.as_secs_f64() / 60.0;
+            // Bots type as fast as they're told, keep them out of the players' numbers.
+            if !lobby.bots.contains_key(&client_id) {
+                // One "word" is 5 characters.
+                metrics
+                    .finish_wpm
+                    .observe(lobby.text_length() as f64 / 5.0 / minutes);
+            }
+            if lobby.winner.is_none() {
+                metrics.races_finished += 1;
+                metrics.race_duration.observe(elapsed.as_secs_f64());
+            }
+        }
+    }
+    let mut buffer =
+        if new_state.current_word >= lobby.words.len() && lobby.state == LobbyState::RaceRunning {
+            lobby.winner = Some(client_id);
+            lobby
+                .transition(Transition::Win)
+                .expect("Running races can be won");
+            info!(winner = client_id, "Lobby finished");
+            ServerMessage::Finished(client_id).encode()
+        } else {
+            if let Some(recording) = &mut lobby.recording {
+                recording.record(Event::state(client_id, new_state));
+            }
+            ServerMessage::State {
+                client_id,
+                state: new_state,
+            }
+            .encode()
+        };
+    if finished.is_some() {
+        buffer += &ServerMessage::Accuracy {
+            client_id,
+            words: lobby.accuracy[&client_id].words(),
+        }
+        .encode();
+    }
+    for client_id in &lobby.clients {
+        if let Some(client) = clients.get_mut(client_id) {
+            // TODO(ashkan): handle errors here.
+            try_send(client, buffer.as_bytes(), command_buffer);
+        }
+    }
+    finished
+}
+
+/// Puts a bot in `lobby` and introduces it to everyone there.
+fn add_bot(
+    lobby: &mut Lobby,
+    bot_id: ClientId,
+    bot: Bot,
+    clients: &mut BTreeMap<ClientId, ClientState>,
+    command_buffer: &mut VecDeque<(ClientId, Command)>,
+) {
+    let mut buffer = ServerMessage::Name {
+        client_id: bot_id,
+        name: bot.name(),
+    }
+    .encode();
+    buffer += &ServerMessage::State {
+        client_id: bot_id,
+        state: bot.state(),
+    }
+    .encode();
+    if let Some(recording) = &mut lobby.recording {
+        recording.record(Event::Name {
+            client: bot_id,
+            name: bot.name(),
+        });
+    }
+    lobby.clients.insert(bot_id);
+    lobby.bots.insert(bot_id, bot);
+    for client_id in &lobby.clients {
+        if let Some(client) = clients.get_mut(client_id) {
+            try_send(client, buffer.as_bytes(), command_buffer);
+        }
+    }
+}
+
+fn try_send(
+    client: &mut ClientState,
+    message: &[u8],
+    command_buffer: &mut VecDeque<(ClientId, Command)>,
+) {
+    if client.stream.write_all(message).is_err() {
+        command_buffer.push_back((client.id, Command::Disconnect(DisconnectReason::WriteError)));
+    }
+}
+
+/// Shuts `lobby` down, sending everyone in it back to having no lobby. The main loop sweeps it
+/// away once it's `Dead`.
+fn close_lobby(
+    lobby: &mut Lobby,
+    clients: &mut BTreeMap<ClientId, ClientState>,
+    command_buffer: &mut VecDeque<(ClientId, Command)>,
+) -> Result<()> {
+    lobby.transition(Transition::Close)?;
+    for client_id in &lobby.clients {
+        if let Some(client) = clients.get_mut(client_id) {
+            client.lobby = None;
+            client.state = PlayerState::default();
+            try_send(
+                client,
+                ServerMessage::LobbyClosed.encode().as_bytes(),
+                command_buffer,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Takes a finished race back to waiting for START, with everyone at the start of the text.
+fn restart_race(lobby: &mut Lobby, clients: &mut BTreeMap<ClientId, ClientState>) -> Result<()> {
+    lobby.transition(Transition::Restart)?;
+    for client_id in &lobby.clients {
+        if let Some(client) = clients.get_mut(client_id) {
+            client.state = PlayerState::default();
+        }
+    }
+    for bot in lobby.bots.values_mut() {
+        bot.reset();
+    }
+    Ok(())
+}
+
+/// Everyone's STATE once `lobby` has been restarted.
+fn restarted_states(lobby: &Lobby) -> String {
+    let mut buffer = String::new();
+    for &client_id in &lobby.clients {
+        buffer += &ServerMessage::State {
+            client_id,
+            state: PlayerState::default(),
+        }
+        .encode();
+    }
+    buffer
+}
+
+/// Looks for a race in the lobbies before the archive, since a lobby's latest race stays there
+/// until the next one starts.
+fn find_recording(
+    race: RaceId,
+    lobbies: &BTreeMap<LobbyCode, Lobby>,
+    recordings: &Recordings,
+) -> Result<Option<Arc<Recording>>> {
+    let live = lobbies
+        .values()
+        .filter_map(|lobby| lobby.recording.as_ref())
+        .find(|recording| recording.id == race);
+    match live {
+        Some(recording) => Ok(Some(Arc::new(recording.clone()))),
+        None => recordings.get(race),
+    }
+}
+
+fn run_admin_command(
+    command: AdminCommand,
+    clients: &mut BTreeMap<ClientId, ClientState>,
+    lobbies: &mut BTreeMap<LobbyCode, Lobby>,
+    banned: &mut HashSet<IpAddr>,
+    recordings: &mut Recordings,
+    command_buffer: &mut VecDeque<(ClientId, Command)>,
+) -> Result<String> {
+    let mut response = String::new();
+    match command {
+        AdminCommand::Lobbies => {
+            for lobby in lobbies.values() {
+                let mut members: Vec<_> = lobby.clients.iter().collect();
+                members.sort();
+                writeln!(
+                    response,
+                    "{} {} leader={} winner={:?} clients={:?}",
+                    lobby.code,
+                    lobby.state.name(),
+                    lobby.leader_id,
+                    lobby.winner,
+                    members
+                )?;
+            }
+        }
+        AdminCommand::Client(client_id) => {
+            let client = clients
+                .get(&client_id)
+                .ok_or_else(|| anyhow::anyhow!("No client {}", client_id))?;
+            writeln!(
+                response,
+                "{} addr={} tls={} lobby={} word={} char={} mistake={} rtt={:?} violations={}",
+                client.id,
+                client.addr,
+                client.stream.is_tls(),
+                client.lobby.map_or("-".into(), |code| code.to_string()),
+                client.state.current_word,
+                client.state.current_completed_character,
+                client.state.did_make_mistake as i32,
+                client.heartbeat.rtt,
+                client.rate_limiter.violations,
+            )?;
+        }
+        AdminCommand::Kick(client_id) | AdminCommand::Ban(client_id) => {
+            let client = clients
+                .get_mut(&client_id)
+                .ok_or_else(|| anyhow::anyhow!("No client {}", client_id))?;
+            if let AdminCommand::Ban(_) = command {
+                banned.insert(client.addr.ip());
+                writeln!(response, "Banned {}", client.addr.ip())?;
+            }
+            try_send(
+                client,
+                ServerMessage::Kicked("Removed by the server operator".to_owned())
+                    .encode()
+                    .as_bytes(),
+                command_buffer,
+            );
+            command_buffer.push_back((client_id, Command::Disconnect(DisconnectReason::Kicked)));
+            writeln!(response, "Kicked {}", client_id)?;
+        }
+        AdminCommand::Unban(addr) => {
+            anyhow::ensure!(banned.remove(&addr), "{} is not banned", addr);
+            writeln!(response, "Unbanned {}", addr)?;
+        }
+        AdminCommand::Close(code) => {
+            let lobby = lobbies
+                .get_mut(&code)
+                .ok_or_else(|| anyhow::anyhow!("No lobby {}", code))?;
+            close_lobby(lobby, clients, command_buffer)?;
+            writeln!(response, "Closed {}", code)?;
+        }
+        AdminCommand::Announce(message) => {
+            let buffer = ServerMessage::Announcement(message).encode();
+            for client in clients.values_mut() {
+                try_send(client, buffer.as_bytes(), command_buffer);
+            }
+            writeln!(response, "Announced to {} clients", clients.len())?;
+        }
+        AdminCommand::Replay(race) => {
+            let recording = find_recording(race, lobbies, recordings)?
+                .ok_or_else(|| anyhow::anyhow!("No race {}", race))?;
+            response = recording.export();
+        }
+    }
+    Ok(response)
+}
+
+/// A bound server, ready to `run`.
+pub struct Server {
+    config: Config,
+    words: Vec<String>,
+    /// Clients on a listener with a TLS config have to speak TLS.
+    listeners: Vec<(TcpListener, Option<Arc<rustls::ServerConfig>>)>,
+    admin_listener: Option<UnixListener>,
+    metrics_server: Option<MetricsServer>,
+    recordings: Recordings,
+    daily: Daily,
+    records: Records,
+    stats: Stats,
+    codes: CodeGenerator,
+}
+
+impl Server {
+    /// Checks `config` and binds every socket it asks for, so callers know where we listen
+    /// before the server starts running.
+    pub fn bind(config: Config) -> Result<Self> {
+        config.validate().context("Invalid configuration")?;
+        if let Some(data_dir) = &config.persistence.data_dir {
+            std::fs::create_dir_all(data_dir).with_context(|| {
+                format!(
+                    "Failed to create persistence.data_dir {}",
+                    data_dir.display()
+                )
+            })?;
+        }
+        let corpus = match &config.corpora.words {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read corpora.words {}", path.display()))?,
+            None => WORDS.to_owned(),
+        };
+        let words: Vec<String> = corpus
+            .lines()
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_owned)
+            .collect();
+        anyhow::ensure!(
+            words.len() >= config.lobby.word_count,
+            "lobby.word_count ({}) is more than the {} words in the corpus",
+            config.lobby.word_count,
+            words.len()
+        );
+        let listener = TcpListener::bind((config.network.address.as_str(), config.network.port))?;
+        listener
+            .set_nonblocking(true)
+            .expect("Cannot set non-blocking");
+        let mut listeners = vec![(listener, None)];
+        if let (Some(port), Some(cert), Some(key)) = (
+            config.network.tls_port,
+            &config.network.tls_cert,
+            &config.network.tls_key,
+        ) {
+            let tls_config = tls::load_server_config(cert, key)?;
+            let listener = TcpListener::bind((config.network.address.as_str(), port))?;
+            listener
+                .set_nonblocking(true)
+                .expect("Cannot set non-blocking");
+            info!(port, "Listening for TLS clients");
+            listeners.push((listener, Some(tls_config)));
+        }
+        let admin_listener = match &config.network.admin_socket {
+            Some(path) => {
+                let listener = admin::bind(path, config.network.admin_socket_mode)?;
+                listener.set_nonblocking(true)?;
+                info!(path = %path.display(), "Admin interface listening");
+                Some(listener)
+            }
+            None => None,
+        };
+        let blocklist = match &config.corpora.code_blocklist {
+            Some(path) => std::fs::read_to_string(path).with_context(|| {
+                format!("Failed to read corpora.code_blocklist {}", path.display())
+            })?,
+            None => codes::BLOCKLIST.to_owned(),
+        };
+        let codes =
+            CodeGenerator::new(&config.lobby, &blocklist).context("Invalid lobby code settings")?;
+        let recordings = Recordings::open(config.persistence.data_dir.as_deref())?;
+        let daily = Daily::open(config.daily.seed, config.persistence.data_dir.as_deref())?;
+        let records = Records::open(config.persistence.data_dir.as_deref())?;
+        let stats = Stats::open(config.persistence.data_dir.as_deref())?;
+        let metrics_server = match config.network.metrics_address {
+            Some(address) => {
+                info!(%address, "Serving metrics on /metrics");
+                Some(MetricsServer::bind(address)?)
+            }
+            None => None,
+        };
+        Ok(Server {
+            config,
+            words,
+            listeners,
+            admin_listener,
+            metrics_server,
+            recordings,
+            daily,
+            records,
+            stats,
+            codes,
+        })
+    }
+
+    /// Where plaintext clients can connect, handy when the config asked for port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].0.local_addr()
+    }
+
+    /// Serves clients until `terminate` is set, then gives running races the configured grace
+    /// period to finish before disconnecting everyone.
+    pub fn run(self, terminate: Arc<AtomicBool>) -> Result<()> {
+        let Server {
+            config,
+            words,
+            mut listeners,
+            admin_listener,
+            mut metrics_server,
+            mut recordings,
+            mut daily,
+            mut records,
+            mut stats,
+            codes,
+        } = self;
+        let ping_interval = config.ping_interval();
+        let ping_timeout = config.ping_timeout();
+        let shutdown_grace = config.shutdown_grace();
+        let limits = &config.limits;
+        let rate_limits = RateLimits {
+            state: RateLimit {
+                rate: limits.state_rate,
+                burst: limits.state_burst,
+            },
+            chat: RateLimit {
+                rate: limits.chat_rate,
+                burst: limits.chat_burst,
+            },
+            other: RateLimit {
+                rate: limits.command_rate,
+                burst: limits.command_burst,
+            },
+            violation_decay: config.violation_decay(),
+        };
+        let mut shutdown_deadline: Option<Instant> = None;
+
+        let mut admin_connections: Vec<AdminConnection> = vec![];
+        let mut metrics = Metrics::new();
+        let mut banned: HashSet<IpAddr> = HashSet::new();
+
+        let mut client_index: ClientId = 0;
+        // Ordered, like the lobbies, so a seeded server handles everyone in the same order every
+        // run.
+        let mut clients = BTreeMap::new();
+
+        let mut lobbies: BTreeMap<LobbyCode, Lobby> = BTreeMap::new();
+
+        // Every random choice goes through this, so --seed makes them all repeatable.
+        let mut rng = match config.lobby.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        let mut command_buffer = VecDeque::new();
+        loop {
+            if terminate.swap(false, Ordering::Relaxed) {
+                if shutdown_deadline.is_none() {
+                    info!(grace = ?shutdown_grace, "Shutting down, waiting for races to finish");
+                    // No new clients can connect while we shut down.
+                    listeners.clear();
+                    shutdown_deadline = Some(Instant::now() + shutdown_grace);
+                    let buffer = ServerMessage::ServerShutdown(shutdown_grace.as_secs()).encode();
+                    for client in clients.values_mut() {
+                        try_send(client, buffer.as_bytes(), &mut command_buffer);
+                    }
+                } else {
+                    warn!("Got a second termination signal, exiting now");
+                    shutdown_deadline = Some(Instant::now());
+                }
+            }
+
+            let mut accepted = vec![];
+            for (listener, tls_config) in &listeners {
+                match listener.accept() {
+                    Ok((socket, addr)) => accepted.push((socket, addr, tls_config.clone())),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        // // wait until network socket is ready, typically implemented
+                        // // via platform-specific APIs such as epoll or IOCP
+                        // wait_for_fd();
+                    }
+                    Err(e) => panic!("encountered IO error: {}", e),
+                }
+            }
+            for (mut socket, addr, tls_config) in accepted {
+                if banned.contains(&addr.ip()) {
+                    info!(%addr, "Refusing banned address");
+                    // TLS clients haven't done their handshake yet, they just get hung up on.
+                    if tls_config.is_none() {
+                        let _ = socket.write_all(ServerMessage::Banned.encode().as_bytes());
+                    }
+                    continue;
+                }
+                let client_id = {
+                    client_index += 1;
+                    client_index
+                };
+                socket
+                    .set_nonblocking(true)
+                    .expect("Failed to set client to non-blocking");
+                socket.set_nodelay(true)?;
+                let mut stream = match tls_config {
+                    Some(tls_config) => match Stream::tls(socket, tls_config) {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            error!(client = client_id, %err, "Failed to start TLS session");
+                            continue;
+                        }
+                    },
+                    None => Stream::Plain(socket),
+                };
+                info!(client = client_id, %addr, tls = stream.is_tls(), "Client connected");
+                let buffer = ServerMessage::Connected(client_id).encode();
+                if stream.write_all(buffer.as_bytes()).is_ok() {
+                    clients.insert(
+                        client_id,
+                        ClientState {
+                            id: client_id,
+                            read_buffer: vec![],
+                            stream,
+                            addr,
+                            state: PlayerState::default(),
+                            lobby: None,
+                            heartbeat: Heartbeat::new(),
+                            name: None,
+                            rate_limiter: RateLimiter::new(rate_limits, Instant::now()),
+                            held_state: None,
+                            playback: None,
+                            span: info_span!("client", client = client_id),
+                        },
+                    );
+                } else {
+                    error!(client = client_id, "Failed to initialize client");
+                }
+            }
+
+            let now = Instant::now();
+            for client in clients.values_mut() {
+                let _span = client.span.clone().entered();
+                // Never buffer more than --max-buffer bytes, anything else waits in the socket until
+                // we've made room.
+                let limit = (limits.max_buffer + 1).saturating_sub(client.read_buffer.len()) as u64;
+                match (&mut client.stream)
+                    .take(limit)
+                    .read_to_end(&mut client.read_buffer)
+                {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        trace!(buffered = client.read_buffer.len(), "Would block");
+                    }
+                    Ok(0) => {
+                        error!("Client hung up :(");
+                        command_buffer
+                            .push_back((client.id, Command::Disconnect(DisconnectReason::HungUp)));
+                    }
+                    Ok(bytes_read) => {
+                        debug!(
+                            bytes_read,
+                            buffered = client.read_buffer.len(),
+                            "Received bytes"
+                        );
+                    }
+                    Err(err) => {
+                        error!(%err, "Client read error");
+                        command_buffer.push_back((
+                            client.id,
+                            Command::Disconnect(DisconnectReason::ReadError),
+                        ));
+                    }
+                }
+                match client.stream.take_error() {
+                    Ok(Some(err)) => {
+                        error!(%err, "Found a socket error");
+                        command_buffer.push_back((
+                            client.id,
+                            Command::Disconnect(DisconnectReason::SocketError),
+                        ));
+                        continue;
+                    }
+                    Err(err) => {
+                        error!(%err, "Failed to check for socket errors");
+                        command_buffer.push_back((
+                            client.id,
+                            Command::Disconnect(DisconnectReason::SocketError),
+                        ));
+                        continue;
+                    }
+                    _ => (),
+                }
+                let lines = match drain_lines(&mut client.read_buffer, limits.max_line_length) {
+                    Ok(lines) => lines,
+                    Err(err) => {
+                        error!(%err, "Protocol error");
+                        let buffer = ServerMessage::ProtocolError(err.to_string()).encode();
+                        let _ = client.stream.write_all(buffer.as_bytes());
+                        command_buffer.push_back((
+                            client.id,
+                            Command::Disconnect(DisconnectReason::ProtocolError),
+                        ));
+                        continue;
+                    }
+                };
+                for line in &lines {
+                    match ClientMessage::decode(line) {
+                        Ok(message) => {
+                            let verdict = match CommandClass::of(&message) {
+                                Some(class) => (class, client.rate_limiter.check(class, now)),
+                                None => (CommandClass::Other, Verdict::Allow),
+                            };
+                            if let ClientMessage::State(state) = message {
+                                client.held_state = match verdict.1 {
+                                    Verdict::Allow => None,
+                                    _ => Some(state),
+                                };
+                            }
+                            match verdict {
+                                (_, Verdict::Allow) => {
+                                    command_buffer.push_back((client.id, Command::Client(message)));
+                                }
+                                (class, Verdict::Throttle) => {
+                                    trace!(class = class.name(), "Throttled");
+                                }
+                                (class, Verdict::Warn(violations)) => {
+                                    warn!(
+                                        class = class.name(),
+                                        dropped = client.rate_limiter.dropped(class),
+                                        violations,
+                                        max_violations = limits.max_violations,
+                                        "Throttling"
+                                    );
+                                    let buffer = ServerMessage::Throttled {
+                                        class: class.name().to_owned(),
+                                        violations,
+                                        max_violations: limits.max_violations,
+                                    }
+                                    .encode();
+                                    if violations > limits.max_violations
+                                        || client.stream.write_all(buffer.as_bytes()).is_err()
+                                    {
+                                        command_buffer.push_back((
+                                            client.id,
+                                            Command::Disconnect(DisconnectReason::RateLimited),
+                                        ));
+                                    }
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            metrics.parse_errors += 1;
+                            error!(
+                                line = %String::from_utf8_lossy(line),
+                                err = ?err,
+                                "Parse error, pressing on..."
+                            );
+                        }
+                    }
+                }
+                if let Some(state) = client.held_state {
+                    if client.rate_limiter.try_take(CommandClass::State, now) {
+                        client.held_state = None;
+                        command_buffer
+                            .push_back((client.id, Command::Client(ClientMessage::State(state))));
+                    }
+                }
+            }
+
+            if let Some(admin_listener) = &admin_listener {
+                match admin_listener.accept() {
+                    Ok(_) if admin_connections.len() >= admin::MAX_CONNECTIONS => {
+                        warn!("Too many admin connections, refusing one");
+                    }
+                    Ok((stream, _)) => match AdminConnection::new(stream, now) {
+                        Ok(connection) => admin_connections.push(connection),
+                        Err(err) => error!(%err, "Failed to set up admin connection"),
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(err) => error!(%err, "Admin socket accept failed"),
+                }
+            }
+            for mut connection in std::mem::take(&mut admin_connections) {
+                let done = connection.poll(now, |line| {
+                    info!(%line, "Admin command");
+                    admin::parse_admin_command(line)
+                        .and_then(|command| {
+                            run_admin_command(
+                                command,
+                                &mut clients,
+                                &mut lobbies,
+                                &mut banned,
+                                &mut recordings,
+                                &mut command_buffer,
+                            )
+                        })
+                        .unwrap_or_else(|err| format!("ERROR {}\n", err))
+                });
+                match done {
+                    Ok(true) => (),
+                    Ok(false) => admin_connections.push(connection),
+                    Err(err) => error!(%err, "Dropping admin connection"),
+                }
+            }
+
+            if let Some(metrics_server) = &mut metrics_server {
+                metrics_server.poll(now, || {
+                    let mut by_state: BTreeMap<&'static str, usize> =
+                        LobbyState::NAMES.iter().map(|&name| (name, 0)).collect();
+                    for lobby in lobbies.values() {
+                        *by_state.entry(lobby.state.name()).or_insert(0) += 1;
+                    }
+                    metrics.render(clients.len(), &by_state)
+                });
+            }
+
+            let now = Instant::now();
+            for client in clients.values_mut() {
+                let _span = client.span.clone().entered();
+                let heartbeat = &mut client.heartbeat;
+                match heartbeat.outstanding {
+                    Some((nonce, sent_at)) if now - sent_at >= ping_timeout => {
+                        error!(nonce, timeout = ?ping_timeout, "No PONG for PING");
+                        command_buffer.push_back((
+                            client.id,
+                            Command::Disconnect(DisconnectReason::PingTimeout),
+                        ));
+                    }
+                    None if now - heartbeat.last_sent >= ping_interval => {
+                        let nonce = heartbeat.next_nonce;
+                        heartbeat.next_nonce += 1;
+                        heartbeat.last_sent = now;
+                        heartbeat.outstanding = Some((nonce, now));
+                        let buffer = ServerMessage::Ping(nonce).encode();
+                        try_send(client, buffer.as_bytes(), &mut command_buffer);
+                    }
+                    _ => (),
+                }
+            }
+
+            // TODO(ashkan): we could group these to avoid redundant hashmap lookups...
+            while let Some((client_id, command)) = command_buffer.pop_front() {
+                *metrics.commands.entry(command.name()).or_insert(0) += 1;
+                let client = match clients.get_mut(&client_id) {
+                    Some(client) => client,
+                    None => {
+                        debug!(client = client_id, ?command, "Client not found");
+                        continue;
+                    }
+                };
+                let _span = info_span!(
+                    "command",
+                    client = client_id,
+                    lobby = client.lobby.as_ref().map(LobbyCode::as_str)
+                )
+                .entered();
+                debug!(?command, "Command");
+                let lobby = client.lobby.and_then(|c| lobbies.get_mut(&c));
+                let action = transition_client(client, lobby.as_deref(), command);
+                if let Some(deadline) = shutdown_deadline {
+                    if let ParseAction::CreateLobby { .. } | ParseAction::StartLobby = action {
+                        warn!(?action, "Ignoring while shutting down");
+                        let remaining = deadline.saturating_duration_since(Instant::now());
+                        let buffer = ServerMessage::ServerShutdown(remaining.as_secs()).encode();
+                        try_send(client, buffer.as_bytes(), &mut command_buffer);
+                        continue;
+                    }
+                }
+                match action {
+                    ParseAction::SendWords {
+                        client_id,
+                        lobby_code,
+                    } => {
+                        assert_eq!(Some(lobby_code), client.lobby);
+                        assert_eq!(client_id, client.id);
+                        let lobby =
+                            lobby.expect("Should've had lobby double checked in transition_client");
+                        let buffer = ServerMessage::Words(lobby.words.clone()).encode();
+                        let message = buffer.as_bytes();
+                        try_send(
+                            clients.get_mut(&client_id).unwrap(),
+                            message,
+                            &mut command_buffer,
+                        );
+                    }
+                    ParseAction::Disconnect { client_id, reason } => {
+                        info!(reason = reason.name(), "Disconnecting");
+                        *metrics.disconnects.entry(reason.name()).or_insert(0) += 1;
+                        if let Some(lobby) = lobby {
+                            lobby.remove_member(client_id);
+                            if let Some(recording) = &mut lobby.recording {
+                                recording.record(Event::Disconnected { client: client_id });
+                            }
+                            // Promote a new leader.
+                            if client_id == lobby.leader_id {
+                                if let Some(leader_id) =
+                                    choose_successor(lobby, config.lobby.succession, &mut rng)
+                                {
+                                    lobby.leader_id = leader_id;
+                                    let buffer = ServerMessage::NewLeader(lobby.leader_id).encode();
+                                    let message = buffer.as_bytes();
+                                    for client_id in &lobby.clients {
+                                        if let Some(client) = clients.get_mut(client_id) {
+                                            try_send(client, message, &mut command_buffer);
+                                        }
+                                    }
+                                }
+                            }
+                            let buffer = ServerMessage::PlayerDisconnected(client_id).encode();
+                            let message = buffer.as_bytes();
+                            for client_id in &lobby.clients {
+                                if let Some(client) = clients.get_mut(client_id) {
+                                    try_send(client, message, &mut command_buffer);
+                                }
+                            }
+                            // Bots don't keep a lobby going on their own.
+                            if !lobby.has_players() {
+                                lobby
+                                    .transition(Transition::Close)
+                                    .expect("Lobbies with players in them are open");
+                            }
+                        }
+                        if let Some(client) = clients.remove(&client_id) {
+                            let limiter = &client.rate_limiter;
+                            if limiter.violations > 0 {
+                                info!(
+                                    state = limiter.dropped(CommandClass::State),
+                                    chat = limiter.dropped(CommandClass::Chat),
+                                    other = limiter.dropped(CommandClass::Other),
+                                    violations = limiter.violations,
+                                    "Rate limiter dropped commands"
+                                );
+                            }
+                        }
+                    }
+                    ParseAction::Noop => trace!("noop"),
+                    ParseAction::StartLobby => {
+                        // TODO(ashkan): this could be empty..?
+                        if let Some(lobby) = lobby {
+                            if lobby.state == LobbyState::RaceFinished {
+                                restart_race(lobby, &mut clients)
+                                    .expect("Finished races can be restarted");
+                            }
+                            let race = recordings.next_id();
+                            info!(race, "Countdown started");
+                            if let Some(recording) = lobby.recording.take() {
+                                recordings.archive(recording);
+                            }
+                            let mut recording = Recording::new(race, lobby.code, &lobby.words);
+                            for &id in &lobby.clients {
+                                let name = match (clients.get(&id), lobby.bots.get(&id)) {
+                                    (Some(client), _) => client.display_name(),
+                                    (_, Some(bot)) => bot.name(),
+                                    _ => continue,
+                                };
+                                recording.record(Event::Name { client: id, name });
+                            }
+                            recording.record(Event::Countdown {
+                                seconds: config.lobby.countdown,
+                            });
+                            lobby.recording = Some(recording);
+                            let buffer = ServerMessage::Countdown(config.lobby.countdown).encode();
+                            let message = buffer.as_bytes();
+                            for client_id in &lobby.clients {
+                                if let Some(client) = clients.get_mut(client_id) {
+                                    // TODO(ashkan): handle errors here.
+                                    try_send(client, message, &mut command_buffer);
+                                }
+                            }
+                            lobby
+                                .transition(Transition::Start(Instant::now() + config.countdown()))
+                                .expect("Checked in transition_client");
+                            lobby.touch();
+                        }
+                    }
+                    ParseAction::CreateLobby {
+                        leader_id,
+                        daily: is_daily,
+                    } => {
+                        let today = daily::today();
+                        let code = match codes.generate(&mut rng, |code| lobbies.contains_key(code))
+                        {
+                            Some(code) => code,
+                            None => {
+                                warn!(lobbies = lobbies.len(), "No free lobby code");
+                                let buffer = ServerMessage::CreateFailed.encode();
+                                try_send(client, buffer.as_bytes(), &mut command_buffer);
+                                continue;
+                            }
+                        };
+                        let text = if is_daily {
+                            daily.words(today, &words, config.lobby.word_count)
+                        } else {
+                            words
+                                .choose_multiple(&mut rng, config.lobby.word_count)
+                                .cloned()
+                                .collect()
+                        };
+                        let day = if is_daily { Some(today) } else { None };
+                        lobbies.insert(code, Lobby::new(code, leader_id, text, day));
+                        info!(lobby = code.as_str(), daily = is_daily, "Lobby created");
+                        let mut buffer = ServerMessage::Created(code).encode();
+                        if is_daily {
+                            buffer += &ServerMessage::Daily(daily::date(today)).encode();
+                        }
+                        let client = clients.get_mut(&client_id).unwrap();
+                        client.lobby = Some(code);
+                        try_send(client, buffer.as_bytes(), &mut command_buffer);
+                        command_buffer
+                            .push_back((client_id, Command::Client(ClientMessage::Words)));
+                        command_buffer.push_back((client_id, Command::ShareState));
+                    }
+                    ParseAction::JoinLobby {
+                        lobby_code,
+                        client_id,
+                    } => {
+                        let ip = client.addr.ip();
+                        // Join random
+                        let lobby_code = if lobby_code == LobbyCode::EMPTY {
+                            let lobby_code = lobbies
+                                .iter()
+                                .filter(|(_, v)| {
+                                    matches!(
+                                        v.state,
+                                        LobbyState::WaitingForStart | LobbyState::Countdown(_)
+                                    )
+                                })
+                                .filter(|(_, v)| !v.banned.contains(&ip))
+                                .map(|(k, _)| k)
+                                .choose(&mut rng);
+                            match lobby_code {
+                                Some(key) => *key,
+                                None => {
+                                    try_send(
+                                        clients.get_mut(&client_id).unwrap(),
+                                        ServerMessage::JoinFailed { banned: false }
+                                            .encode()
+                                            .as_bytes(),
+                                        &mut command_buffer,
+                                    );
+                                    continue;
+                                }
+                            }
+                        } else {
+                            lobby_code
+                        };
+                        // Closed lobbies are only still around until the end of the loop.
+                        match lobbies
+                            .get_mut(&lobby_code)
+                            .filter(|lobby| lobby.state != LobbyState::Dead)
+                        {
+                            Some(lobby) if lobby.banned.contains(&ip) => {
+                                info!(%ip, lobby = lobby_code.as_str(), "Refusing banned address");
+                                let client = clients.get_mut(&client_id).unwrap();
+                                client.lobby = None;
+                                try_send(
+                                    client,
+                                    ServerMessage::JoinFailed { banned: true }
+                                        .encode()
+                                        .as_bytes(),
+                                    &mut command_buffer,
+                                );
+                            }
+                            Some(lobby) => {
+                                // For join random
+                                client.lobby = Some(lobby_code);
+                                info!(lobby = lobby_code.as_str(), "Joined lobby");
+                                // TODO(ashkan): check client didn't get inserted twice.
+                                lobby.clients.insert(client_id);
+                                lobby.touch();
+                                if let Some(recording) = &mut lobby.recording {
+                                    recording.record(Event::Name {
+                                        client: client_id,
+                                        name: client.display_name(),
+                                    });
+                                }
+                                lobby
+                                    .joined_at
+                                    .entry(client_id)
+                                    .or_insert_with(Instant::now);
+                                command_buffer
+                                    .push_back((client_id, Command::Client(ClientMessage::Words)));
+                                for client_id in &lobby.clients {
+                                    if clients.contains_key(client_id) {
+                                        command_buffer.push_back((*client_id, Command::ShareState));
+                                    }
+                                }
+                                let buffer = ServerMessage::Joined(lobby_code).encode();
+                                try_send(
+                                    clients.get_mut(&client_id).unwrap(),
+                                    buffer.as_bytes(),
+                                    &mut command_buffer,
+                                );
+                                // Introduce the newcomer and everyone else to each other.
+                                let names: Vec<_> = lobby
+                                    .clients
+                                    .iter()
+                                    .filter_map(|id| clients.get(id))
+                                    .filter(|client| client.name.is_some())
+                                    .map(|client| (client.id, client.display_name()))
+                                    .chain(lobby.bots.iter().map(|(&id, bot)| (id, bot.name())))
+                                    .collect();
+                                for (id, name) in names {
+                                    let buffer = ServerMessage::Name {
+                                        client_id: id,
+                                        name,
+                                    }
+                                    .encode();
+                                    if id == client_id {
+                                        for other_id in &lobby.clients {
+                                            if let Some(other) = clients.get_mut(other_id) {
+                                                if other.id != client_id {
+                                                    try_send(
+                                                        other,
+                                                        buffer.as_bytes(),
+                                                        &mut command_buffer,
+                                                    );
+                                                }
+                                            }
+                                        }
+                                    } else {
+                                        try_send(
+                                            clients.get_mut(&client_id).unwrap(),
+                                            buffer.as_bytes(),
+                                            &mut command_buffer,
+                                        );
+                                    }
+                                }
+                                // Bots don't get STATE commands queued for them like players.
+                                for (&id, bot) in &lobby.bots {
+                                    let buffer = ServerMessage::State {
+                                        client_id: id,
+                                        state: bot.state(),
+                                    }
+                                    .encode();
+                                    try_send(
+                                        clients.get_mut(&client_id).unwrap(),
+                                        buffer.as_bytes(),
+                                        &mut command_buffer,
+                                    );
+                                }
+                                for line in &lobby.chat {
+                                    try_send(
+                                        clients.get_mut(&client_id).unwrap(),
+                                        line.as_bytes(),
+                                        &mut command_buffer,
+                                    );
+                                }
+                            }
+                            None => {
+                                // TODO(ashkan): return failed code?
+                                let client = clients.get_mut(&client_id).unwrap();
+                                client.lobby = None;
+                                try_send(
+                                    client,
+                                    ServerMessage::JoinFailed { banned: false }
+                                        .encode()
+                                        .as_bytes(),
+                                    &mut command_buffer,
+                                );
+                            }
+                        }
+                    }
+                    ParseAction::UpdatedState {
+                        lobby_code,
+                        client_id,
+                        new_state,
+                    } => {
+                        let lobby = lobbies
+                            .get_mut(&lobby_code)
+                            .expect("Should've had lobby double checked in parse_client");
+                        if let (
+                            LobbyState::RaceRunning | LobbyState::RaceFinished,
+                            Some(race_started),
+                        ) = (&lobby.state, lobby.race_started)
+                        {
+                            lobby
+                                .timelines
+                                .entry(client_id)
+                                .or_default()
+                                .push((race_started.elapsed(), new_state));
+                        }
+                        let finished = record_state(
+                            lobby,
+                            client_id,
+                            new_state,
+                            &mut clients,
+                            &mut metrics,
+                            &mut command_buffer,
+                        );
+                        if let (Some(time), Some(client)) = (finished, clients.get(&client_id)) {
+                            let timeline = lobby.timelines.remove(&client_id).unwrap_or_default();
+                            stats.record(&client.display_name(), &lobby.words, &timeline);
+                            let run = Run {
+                                player: client.display_name(),
+                                time,
+                                timeline: Arc::new(timeline),
+                            };
+                            if records.record(&lobby.words, run) {
+                                info!(?time, "New personal best");
+                            }
+                            if let (true, Some(day)) =
+                                (lobby.daily_entrants.remove(&client_id), lobby.daily)
+                            {
+                                let place = daily.finish(day, &client.display_name(), time);
+                                info!(?time, place, "Daily challenge finished");
+                            }
+                        }
+                    }
+                    ParseAction::KickPlayer {
+                        lobby_code,
+                        client_id,
+                        ban,
+                    } => {
+                        let lobby = lobbies
+                            .get_mut(&lobby_code)
+                            .expect("Should've had lobby double checked in transition_client");
+                        lobby.remove_member(client_id);
+                        if let Some(recording) = &mut lobby.recording {
+                            recording.record(Event::Kicked { client: client_id });
+                        }
+                        if lobby.bots.remove(&client_id).is_some() {
+                            info!(target = client_id, "Removed bot from lobby");
+                        }
+                        if let Some(client) = clients.get_mut(&client_id) {
+                            info!(target = client_id, ban, "Removed player from lobby");
+                            if ban {
+                                lobby.banned.insert(client.addr.ip());
+                            }
+                            client.lobby = None;
+                            client.state = PlayerState::default();
+                            let reason = if ban {
+                                "Banned by the lobby leader"
+                            } else {
+                                "Removed by the lobby leader"
+                            };
+                            let buffer = ServerMessage::Kicked(reason.to_owned()).encode();
+                            try_send(client, buffer.as_bytes(), &mut command_buffer);
+                        }
+                        let buffer = ServerMessage::PlayerKicked(client_id).encode();
+                        let message = buffer.as_bytes();
+                        for client_id in &lobby.clients {
+                            if let Some(client) = clients.get_mut(client_id) {
+                                try_send(client, message, &mut command_buffer);
+                            }
+                        }
+                    }
+                    ParseAction::PromoteLeader {
+                        lobby_code,
+                        client_id,
+                    } => {
+                        let lobby = lobbies
+                            .get_mut(&lobby_code)
+                            .expect("Should've had lobby double checked in transition_client");
+                        info!(
+                            from = lobby.leader_id,
+                            to = client_id,
+                            "Leadership handed over"
+                        );
+                        lobby.leader_id = client_id;
+                        let buffer = ServerMessage::NewLeader(lobby.leader_id).encode();
+                        let message = buffer.as_bytes();
+                        for client_id in &lobby.clients {
+                            if let Some(client) = clients.get_mut(client_id) {
+                                try_send(client, message, &mut command_buffer);
+                            }
+                        }
+                    }
+                    ParseAction::Chat {
+                        lobby_code,
+                        client_id,
+                        message,
+                    } => {
+                        let lobby = lobbies
+                            .get_mut(&lobby_code)
+                            .expect("Should've had lobby double checked in transition_client");
+                        let buffer = ServerMessage::Chat {
+                            client_id,
+                            name: client.display_name(),
+                            message,
+                        }
+                        .encode();
+                        for client_id in &lobby.clients {
+                            if let Some(client) = clients.get_mut(client_id) {
+                                try_send(client, buffer.as_bytes(), &mut command_buffer);
+                            }
+                        }
+                        if lobby.chat.len() == CHAT_BACKLOG {
+                            lobby.chat.pop_front();
+                        }
+                        lobby.chat.push_back(buffer);
+                    }
+                    ParseAction::Rename {
+                        lobby_code,
+                        client_id,
+                    } => {
+                        let lobby = lobbies
+                            .get_mut(&lobby_code)
+                            .expect("Should've had lobby double checked in transition_client");
+                        if let Some(recording) = &mut lobby.recording {
+                            recording.record(Event::Name {
+                                client: client_id,
+                                name: client.display_name(),
+                            });
+                        }
+                        let buffer = ServerMessage::Name {
+                            client_id,
+                            name: client.display_name(),
+                        }
+                        .encode();
+                        for client_id in &lobby.clients {
+                            if let Some(client) = clients.get_mut(client_id) {
+                                try_send(client, buffer.as_bytes(), &mut command_buffer);
+                            }
+                        }
+                    }
+                    ParseAction::ReportLatency {
+                        lobby_code,
+                        client_id,
+                        rtt,
+                    } => {
+                        let lobby = lobbies
+                            .get(&lobby_code)
+                            .expect("Should've had lobby double checked in transition_client");
+                        let buffer = ServerMessage::Latency {
+                            client_id,
+                            millis: rtt.as_millis() as u64,
+                        }
+                        .encode();
+                        let message = buffer.as_bytes();
+                        for client_id in &lobby.clients {
+                            if let Some(client) = clients.get_mut(client_id) {
+                                try_send(client, message, &mut command_buffer);
+                            }
+                        }
+                    }
+                    ParseAction::AddBot {
+                        lobby_code,
+                        wpm,
+                        variance,
+                    } => {
+                        let lobby = lobbies
+                            .get_mut(&lobby_code)
+                            .expect("Should've had lobby double checked in transition_client");
+                        // Bots share the id space with clients so players can't tell them apart.
+                        client_index += 1;
+                        info!(bot = client_index, wpm, ?variance, "Bot added");
+                        add_bot(
+                            lobby,
+                            client_index,
+                            Bot::typist(wpm, variance),
+                            &mut clients,
+                            &mut command_buffer,
+                        );
+                    }
+                    ParseAction::AddGhost { lobby_code, record } => {
+                        let lobby = lobbies
+                            .get_mut(&lobby_code)
+                            .expect("Should've had lobby double checked in transition_client");
+                        let run = if record {
+                            records.record_holder(&lobby.words)
+                        } else {
+                            records.personal_best(&lobby.words, &client.display_name())
+                        };
+                        match run {
+                            Some(run) => {
+                                client_index += 1;
+                                info!(
+                                    bot = client_index,
+                                    player = run.player.as_str(),
+                                    time = ?run.time,
+                                    "Ghost added"
+                                );
+                                let ghost = Bot::ghost(run);
+                                add_bot(
+                                    lobby,
+                                    client_index,
+                                    ghost,
+                                    &mut clients,
+                                    &mut command_buffer,
+                                );
+                            }
+                            None => try_send(
+                                client,
+                                ServerMessage::NoGhost.encode().as_bytes(),
+                                &mut command_buffer,
+                            ),
+                        }
+                    }
+                    ParseAction::Replay { race, speed } => {
+                        let recording =
+                            find_recording(race, &lobbies, &recordings).unwrap_or_else(|err| {
+                                error!(race, err = ?err, "Failed to load recording");
+                                None
+                            });
+                        if let Some(recording) = recording {
+                            info!(race, speed, "Replaying race");
+                            let (playback, messages) =
+                                Playback::start(recording, speed, Instant::now());
+                            for message in messages {
+                                try_send(client, message.encode().as_bytes(), &mut command_buffer);
+                            }
+                            client.playback = Some(playback);
+                        } else {
+                            let buffer = ServerMessage::NoReplay(race).encode();
+                            try_send(client, buffer.as_bytes(), &mut command_buffer);
+                        }
+                    }
+                    ParseAction::StartDaily { lobby_code } => {
+                        let lobby = lobbies
+                            .get_mut(&lobby_code)
+                            .expect("Should've had lobby double checked in transition_client");
+                        let mut buffer = String::new();
+                        // Anyone still typing the old text would be scored against the new one.
+                        if lobby.state == LobbyState::RaceFinished {
+                            restart_race(lobby, &mut clients).expect("Finished races can restart");
+                            buffer += &restarted_states(lobby);
+                        }
+                        let today = daily::today();
+                        info!(
+                            date = daily::date(today).as_str(),
+                            "Switched to the daily challenge"
+                        );
+                        lobby.words = daily.words(today, &words, config.lobby.word_count);
+                        lobby.daily = Some(today);
+                        buffer += &ServerMessage::Daily(daily::date(today)).encode();
+                        buffer += &ServerMessage::Words(lobby.words.clone()).encode();
+                        for client_id in &lobby.clients {
+                            if let Some(client) = clients.get_mut(client_id) {
+                                try_send(client, buffer.as_bytes(), &mut command_buffer);
+                            }
+                        }
+                    }
+                    ParseAction::SendDailyBoard => {
+                        let today = daily::today();
+                        let buffer = ServerMessage::DailyBoard {
+                            date: daily::date(today),
+                            finishes: daily
+                                .rankings(today)
+                                .iter()
+                                .map(|finish| (finish.player.clone(), finish.time_ms))
+                                .collect(),
+                        }
+                        .encode();
+                        try_send(client, buffer.as_bytes(), &mut command_buffer);
+                    }
+                    ParseAction::Invalid { command, state } => {
+                        let buffer = ServerMessage::Invalid {
+                            command: command.to_owned(),
+                            state: state.to_owned(),
+                        }
+                        .encode();
+                        try_send(client, buffer.as_bytes(), &mut command_buffer);
+                    }
+                    ParseAction::SendStats => {
+                        let buffer =
+                            ServerMessage::Stats(stats.report(&client.display_name())).encode();
+                        try_send(client, buffer.as_bytes(), &mut command_buffer);
+                    }
+                    ParseAction::RestartLobby => {
+                        let lobby =
+                            lobby.expect("Should've had lobby checked in transition_client");
+                        restart_race(lobby, &mut clients).expect("Checked in transition_client");
+                        info!("Race restarted");
+                        let buffer = restarted_states(lobby);
+                        for client_id in &lobby.clients {
+                            if let Some(client) = clients.get_mut(client_id) {
+                                try_send(client, buffer.as_bytes(), &mut command_buffer);
+                            }
+                        }
+                    }
+                }
+            }
+
+            for lobby in lobbies.values_mut() {
+                match lobby.state {
+                    LobbyState::Countdown(deadline) if deadline <= Instant::now() => {
+                        let mut buffer = ServerMessage::Starting.encode();
+                        if let Some(recording) = &mut lobby.recording {
+                            recording.record(Event::Start);
+                            buffer += &ServerMessage::Race(recording.id).encode();
+                        }
+                        let message = buffer.as_bytes();
+                        for client_id in &lobby.clients {
+                            if let Some(client) = clients.get_mut(client_id) {
+                                // TODO(ashkan): handle errors here.
+                                try_send(client, message, &mut command_buffer);
+                            }
+                        }
+                        info_span!("lobby", lobby = lobby.code.as_str())
+                            .in_scope(|| info!("Race started"));
+                        lobby.transition(Transition::Go).expect("Counting down");
+                        lobby.touch();
+                        lobby.race_started = Some(Instant::now());
+                        lobby.winner = None;
+                        lobby.finishers.clear();
+                        lobby.timelines.clear();
+                        lobby.accuracy.clear();
+                        lobby.daily_entrants.clear();
+                        if let Some(day) = lobby.daily {
+                            for client_id in &lobby.clients {
+                                if let Some(client) = clients.get(client_id) {
+                                    if daily.enter(day, client.addr.ip()) {
+                                        lobby.daily_entrants.insert(*client_id);
+                                    }
+                                }
+                            }
+                        }
+                        for bot in lobby.bots.values_mut() {
+                            bot.start(Instant::now());
+                        }
+                    }
+                    LobbyState::RaceRunning | LobbyState::RaceFinished
+                        if !lobby.bots.is_empty() =>
+                    {
+                        let _span = info_span!("lobby", lobby = lobby.code.as_str()).entered();
+                        let now = Instant::now();
+                        let mut updates = vec![];
+                        for (&bot_id, bot) in lobby.bots.iter_mut() {
+                            if let Some(state) = bot.tick(&lobby.words, now, &mut rng) {
+                                updates.push((bot_id, state));
+                            }
+                        }
+                        for (bot_id, state) in updates {
+                            record_state(
+                                lobby,
+                                bot_id,
+                                state,
+                                &mut clients,
+                                &mut metrics,
+                                &mut command_buffer,
+                            );
+                        }
+                    }
+                    _ => (),
+                }
+            }
+
+            let now = Instant::now();
+            let mut expired = vec![];
+            for lobby in lobbies.values_mut() {
+                let timeout = match lobby.idle_timeout(&config) {
+                    Some(timeout) => timeout,
+                    None => continue,
+                };
+                let idle = now.saturating_duration_since(lobby.last_activity);
+                // Always warn first, even when the warning comes too late to be of much use.
+                if !lobby.expiry_warned && idle + config.expiry_warning() >= timeout {
+                    lobby.expiry_warned = true;
+                    let remaining = timeout.saturating_sub(idle);
+                    let buffer =
+                        ServerMessage::LobbyExpiring(remaining.as_secs_f64().ceil() as u64)
+                            .encode();
+                    for client_id in &lobby.clients {
+                        if let Some(client) = clients.get_mut(client_id) {
+                            try_send(client, buffer.as_bytes(), &mut command_buffer);
+                        }
+                    }
+                } else if lobby.expiry_warned && idle >= timeout {
+                    expired.push(lobby.code);
+                }
+            }
+            for code in expired {
+                if let Some(lobby) = lobbies.get_mut(&code) {
+                    info!(
+                        lobby = code.as_str(),
+                        state = lobby.state.name(),
+                        "Lobby expired"
+                    );
+                    metrics.lobbies_expired += 1;
+                    close_lobby(lobby, &mut clients, &mut command_buffer)
+                        .expect("Only open lobbies expire");
+                }
+            }
+
+            for client in clients.values_mut() {
+                if let Some(playback) = &mut client.playback {
+                    let mut buffer = String::new();
+                    for message in playback.tick(Instant::now()) {
+                        buffer += &message.encode();
+                    }
+                    if playback.is_done() {
+                        buffer += &ServerMessage::ReplayEnd(playback.race()).encode();
+                        client.playback = None;
+                    }
+                    if !buffer.is_empty() {
+                        try_send(client, buffer.as_bytes(), &mut command_buffer);
+                    }
+                }
+            }
+
+            lobbies.retain(|code, lobby| {
+                let alive = lobby.state != LobbyState::Dead;
+                if !alive {
+                    info!(lobby = code.as_str(), "Lobby closed");
+                    if let Some(recording) = lobby.recording.take() {
+                        recordings.archive(recording);
+                    }
+                }
+                alive
+            });
+
+            if let Some(deadline) = shutdown_deadline {
+                let racing = lobbies.values().any(|lobby| {
+                    matches!(
+                        lobby.state,
+                        LobbyState::Countdown(_) | LobbyState::RaceRunning
+                    )
+                });
+                if !racing || Instant::now() >= deadline {
+                    break;
+                }
+            }
+
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        for lobby in lobbies.values_mut() {
+            if let Some(recording) = lobby.recording.take() {
+                recordings.archive(recording);
+            }
+        }
+        info!(clients = clients.len(), "Closing client connections");
+        for client in clients.values_mut() {
+            // Best effort, the process is going away regardless.
+            let _ = client.stream.flush();
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+        if let Some(path) = &config.network.admin_socket {
+            let _ = std::fs::remove_file(path);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Members 1 to 4 joined in that order, 1 leads and 4 is a bot.
+    fn lobby() -> Lobby {
+        let start = Instant::now();
+        let mut lobby = Lobby::new(LobbyCode::new(b"ABCDE").unwrap(), 1, vec![], None);
+        for id in 1..=4 {
+            lobby.clients.insert(id);
+            lobby.joined_at.insert(id, start + Duration::from_secs(id));
+        }
+        lobby.bots.insert(4, Bot::typist(300, None));
+        lobby
+    }
+
+    #[test]
+    fn successors_follow_the_policy() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut lobby = lobby();
+        lobby.remove_member(1);
+        let successor =
+            |lobby: &Lobby, policy, rng: &mut StdRng| choose_successor(lobby, policy, rng);
+        assert_eq!(
+            successor(&lobby, SuccessionPolicy::Oldest, &mut rng),
+            Some(2)
+        );
+        // Nobody has finished a race, so the oldest.
+        assert_eq!(
+            successor(&lobby, SuccessionPolicy::Fastest, &mut rng),
+            Some(2)
+        );
+        let mut picked = HashSet::new();
+        for _ in 0..100 {
+            picked.insert(successor(&lobby, SuccessionPolicy::Random, &mut rng).unwrap());
+        }
+        assert_eq!(picked, [2, 3].iter().copied().collect());
+
+        lobby.remove_member(2);
+        lobby.remove_member(3);
+        for &policy in &[
+            SuccessionPolicy::Oldest,
+            SuccessionPolicy::Random,
+            SuccessionPolicy::Fastest,
+        ] {
+            assert_eq!(successor(&lobby, policy, &mut rng), None);
+        }
+    }
+
+    #[test]
+    fn fastest_successor_won_the_last_race() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut lobby = lobby();
+        lobby.clients.insert(5);
+        lobby
+            .joined_at
+            .insert(5, Instant::now() + Duration::from_secs(5));
+        lobby.transition(Transition::Start(Instant::now())).unwrap();
+        lobby.transition(Transition::Go).unwrap();
+        // The bot won, then 3 beat 1 and 2 never finished.
+        lobby.finishers = vec![
+            (4, Duration::from_secs(5)),
+            (3, Duration::from_secs(10)),
+            (1, Duration::from_secs(12)),
+        ];
+        lobby.transition(Transition::Win).unwrap();
+        lobby.remove_member(1);
+        assert_eq!(
+            choose_successor(&lobby, SuccessionPolicy::Fastest, &mut rng),
+            Some(3)
+        );
+
+        // Still the last race's finishers once the lobby is back to waiting.
+        restart_race(&mut lobby, &mut BTreeMap::new()).unwrap();
+        assert_eq!(
+            choose_successor(&lobby, SuccessionPolicy::Fastest, &mut rng),
+            Some(3)
+        );
+        // After the finishers it's the oldest who didn't finish.
+        lobby.remove_member(3);
+        assert_eq!(
+            choose_successor(&lobby, SuccessionPolicy::Fastest, &mut rng),
+            Some(2)
+        );
+    }
+}
@@ -0,0 +1,300 @@
+//! What state a lobby is in, how it gets from one to the next, and which commands its members can
+//! send in each. Nothing else changes `Lobby::state`.
+//!
+//! ```text
+//! WaitingForStart --Start--> Countdown --Go--> RaceRunning --Win--> RaceFinished
+//!        ^                                                              |
+//!        +-------------------------Restart------------------------------+
+//! ```
+//!
+//! Any live lobby can be closed, which makes it `Dead` until the main loop sweeps it away.
+use anyhow::{bail, Result};
+use protocol::ClientMessage;
+use std::time::Instant;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LobbyState {
+    WaitingForStart,
+    /// Counting down to the race starting at this instant.
+    Countdown(Instant),
+    RaceRunning,
+    /// Someone won. Everyone else can still finish.
+    RaceFinished,
+    /// Closed, with everyone moved out.
+    Dead,
+}
+
+/// Something that moves a lobby on to another state.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Transition {
+    /// The leader started a countdown to this instant.
+    Start(Instant),
+    /// The countdown ran out.
+    Go,
+    /// Someone typed the whole text.
+    Win,
+    /// Back to waiting for START once a race is over.
+    Restart,
+    /// The last player left, the lobby expired or an operator closed it.
+    Close,
+}
+
+impl Transition {
+    fn name(self) -> &'static str {
+        match self {
+            Transition::Start(_) => "start",
+            Transition::Go => "go",
+            Transition::Win => "win",
+            Transition::Restart => "restart",
+            Transition::Close => "close",
+        }
+    }
+}
+
+/// What a lobby makes of a command from one of its members.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Admission {
+    Accept,
+    /// Harmless but pointless now, like a keystroke that arrives after the race was restarted.
+    Ignore,
+    /// Makes no sense in this state, like START during a race. The client is told so with INVALID.
+    Reject,
+}
+
+impl LobbyState {
+    pub const NAMES: &'static [&'static str] = &[
+        "WaitingForStart",
+        "Countdown",
+        "RaceRunning",
+        "RaceFinished",
+        "Dead",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LobbyState::WaitingForStart => "WaitingForStart",
+            LobbyState::Countdown(_) => "Countdown",
+            LobbyState::RaceRunning => "RaceRunning",
+            LobbyState::RaceFinished => "RaceFinished",
+            LobbyState::Dead => "Dead",
+        }
+    }
+
+    /// The state `transition` leads to from this one.
+    pub fn next(self, transition: Transition) -> Result<LobbyState> {
+        use LobbyState::*;
+        Ok(match (self, transition) {
+            (WaitingForStart, Transition::Start(at)) => Countdown(at),
+            (Countdown(_), Transition::Go) => RaceRunning,
+            (RaceRunning, Transition::Win) => RaceFinished,
+            (RaceFinished, Transition::Restart) => WaitingForStart,
+            (Dead, Transition::Close) => bail!("The lobby is already closed"),
+            (_, Transition::Close) => Dead,
+            (state, transition) => bail!(
+                "Invalid lobby transition: can't {} while {}",
+                transition.name(),
+                state.name()
+            ),
+        })
+    }
+
+    /// Whether a member can send `command` now.
+    pub fn admit(&self, command: &ClientMessage) -> Admission {
+        use Admission::*;
+        use LobbyState::*;
+        if let Dead = self {
+            return Reject;
+        }
+        match command {
+            // Starting again after a race restarts it first.
+            ClientMessage::Start => match self {
+                WaitingForStart | RaceFinished => Accept,
+                _ => Reject,
+            },
+            ClientMessage::Restart => match self {
+                RaceFinished => Accept,
+                _ => Reject,
+            },
+            // Stragglers' keystrokes can cross a restart on the wire.
+            ClientMessage::State(_) => match self {
+                RaceRunning | RaceFinished => Accept,
+                _ => Ignore,
+            },
+            // The text can't change under a countdown or a race. Finished races are restarted
+            // first, since some players may still be typing.
+            ClientMessage::Daily => match self {
+                WaitingForStart | RaceFinished => Accept,
+                _ => Ignore,
+            },
+            // Nobody joins a race that's already being run.
+            ClientMessage::AddBot { .. } | ClientMessage::AddGhost { .. } => match self {
+                RaceRunning => Ignore,
+                _ => Accept,
+            },
+            ClientMessage::Kick(_)
+            | ClientMessage::Ban(_)
+            | ClientMessage::Promote(_)
+            | ClientMessage::Words
+            | ClientMessage::Chat(_)
+            | ClientMessage::Pong(_)
+            | ClientMessage::Name(_)
+            | ClientMessage::DailyBoard
+            | ClientMessage::Stats => Accept,
+            ClientMessage::Create | ClientMessage::Replay { .. } => Ignore,
+            ClientMessage::Join(_) | ClientMessage::JoinRandom => Reject,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{LobbyCode, PlayerState};
+    use Admission::*;
+    use LobbyState::*;
+
+    fn states(now: Instant) -> [LobbyState; 5] {
+        [
+            WaitingForStart,
+            Countdown(now),
+            RaceRunning,
+            RaceFinished,
+            Dead,
+        ]
+    }
+
+    #[test]
+    fn transitions() {
+        let now = Instant::now();
+        let table: &[(Transition, [Option<LobbyState>; 5])] = &[
+            (
+                Transition::Start(now),
+                [Some(Countdown(now)), None, None, None, None],
+            ),
+            (Transition::Go, [None, Some(RaceRunning), None, None, None]),
+            (
+                Transition::Win,
+                [None, None, Some(RaceFinished), None, None],
+            ),
+            (
+                Transition::Restart,
+                [None, None, None, Some(WaitingForStart), None],
+            ),
+            (
+                Transition::Close,
+                [Some(Dead), Some(Dead), Some(Dead), Some(Dead), None],
+            ),
+        ];
+        for &(transition, expected) in table {
+            for (state, expected) in states(now).iter().zip(&expected) {
+                assert_eq!(
+                    state.next(transition).ok(),
+                    *expected,
+                    "{:?} from {:?}",
+                    transition,
+                    state
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn admits_commands() {
+        let now = Instant::now();
+        let table: &[(ClientMessage, [Admission; 5])] = &[
+            (
+                ClientMessage::Start,
+                [Accept, Reject, Reject, Accept, Reject],
+            ),
+            (
+                ClientMessage::Restart,
+                [Reject, Reject, Reject, Accept, Reject],
+            ),
+            (
+                ClientMessage::State(PlayerState::default()),
+                [Ignore, Ignore, Accept, Accept, Reject],
+            ),
+            (
+                ClientMessage::Daily,
+                [Accept, Ignore, Ignore, Accept, Reject],
+            ),
+            (
+                ClientMessage::AddBot {
+                    wpm: 60,
+                    variance: None,
+                },
+                [Accept, Accept, Ignore, Accept, Reject],
+            ),
+            (
+                ClientMessage::AddGhost { record: false },
+                [Accept, Accept, Ignore, Accept, Reject],
+            ),
+            (
+                ClientMessage::Kick(2),
+                [Accept, Accept, Accept, Accept, Reject],
+            ),
+            (
+                ClientMessage::Ban(2),
+                [Accept, Accept, Accept, Accept, Reject],
+            ),
+            (
+                ClientMessage::Promote(2),
+                [Accept, Accept, Accept, Accept, Reject],
+            ),
+            (
+                ClientMessage::Words,
+                [Accept, Accept, Accept, Accept, Reject],
+            ),
+            (
+                ClientMessage::Chat("hi".to_owned()),
+                [Accept, Accept, Accept, Accept, Reject],
+            ),
+            (
+                ClientMessage::Pong(0),
+                [Accept, Accept, Accept, Accept, Reject],
+            ),
+            (
+                ClientMessage::Name("ashkan".to_owned()),
+                [Accept, Accept, Accept, Accept, Reject],
+            ),
+            (
+                ClientMessage::DailyBoard,
+                [Accept, Accept, Accept, Accept, Reject],
+            ),
+            (
+                ClientMessage::Stats,
+                [Accept, Accept, Accept, Accept, Reject],
+            ),
+            (
+                ClientMessage::Create,
+                [Ignore, Ignore, Ignore, Ignore, Reject],
+            ),
+            (
+                ClientMessage::Replay {
+                    race: 1,
+                    speed: None,
+                },
+                [Ignore, Ignore, Ignore, Ignore, Reject],
+            ),
+            (
+                ClientMessage::Join(LobbyCode::new(b"ABCDE").unwrap()),
+                [Reject, Reject, Reject, Reject, Reject],
+            ),
+            (
+                ClientMessage::JoinRandom,
+                [Reject, Reject, Reject, Reject, Reject],
+            ),
+        ];
+        for (command, expected) in table {
+            for (state, expected) in states(now).iter().zip(expected) {
+                assert_eq!(
+                    state.admit(command),
+                    *expected,
+                    "{} while {:?}",
+                    command.name(),
+                    state
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,393 @@
+//! Counters about the running server, served to Prometheus over a tiny HTTP endpoint.
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, prelude::*};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+pub struct Histogram {
+    /// Upper bounds of each bucket, not counting the implicit `+Inf` one.
+    bounds: &'static [f64],
+    /// Non-cumulative count of observations in each bucket, `+Inf` last.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        cumulative += self.counts[self.bounds.len()];
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+const RACE_DURATION_BOUNDS: &[f64] = &[10.0, 20.0, 30.0, 45.0, 60.0, 90.0, 120.0, 180.0, 300.0];
+const WPM_BOUNDS: &[f64] = &[20.0, 40.0, 60.0, 80.0, 100.0, 120.0, 150.0, 200.0];
+
+pub struct Metrics {
+    pub commands: BTreeMap<&'static str, u64>,
+    pub parse_errors: u64,
+    pub disconnects: BTreeMap<&'static str, u64>,
+    pub races_finished: u64,
+    pub lobbies_expired: u64,
+    pub race_duration: Histogram,
+    pub finish_wpm: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            commands: BTreeMap::new(),
+            parse_errors: 0,
+            disconnects: BTreeMap::new(),
+            races_finished: 0,
+            lobbies_expired: 0,
+            race_duration: Histogram::new(RACE_DURATION_BOUNDS),
+            finish_wpm: Histogram::new(WPM_BOUNDS),
+        }
+    }
+
+    /// Renders everything in the Prometheus text exposition format. Gauges are passed in since
+    /// they're cheaper to compute on demand than to keep up to date.
+    pub fn render(
+        &self,
+        connected_clients: usize,
+        lobbies: &BTreeMap<&'static str, usize>,
+    ) -> String {
+        let mut out = String::new();
+        macro_rules! metric {
+            ($name:expr, $kind:expr, $help:expr) => {
+                let _ = writeln!(out, "# HELP {} {}", $name, $help);
+                let _ = writeln!(out, "# TYPE {} {}", $name, $kind);
+            };
+        }
+        metric!(
+            "typeracer_connected_clients",
+            "gauge",
+            "Clients currently connected."
+        );
+        let _ = writeln!(out, "typeracer_connected_clients {}", connected_clients);
+        metric!("typeracer_lobbies", "gauge", "Lobbies by state.");
+        for (state, count) in lobbies {
+            let _ = writeln!(out, "typeracer_lobbies{{state=\"{}\"}} {}", state, count);
+        }
+        metric!(
+            "typeracer_commands_total",
+            "counter",
+            "Commands processed by type."
+        );
+        for (command, count) in &self.commands {
+            let _ = writeln!(
+                out,
+                "typeracer_commands_total{{command=\"{}\"}} {}",
+                command, count
+            );
+        }
+        metric!(
+            "typeracer_parse_errors_total",
+            "counter",
+            "Input lines we couldn't parse."
+        );
+        let _ = writeln!(out, "typeracer_parse_errors_total {}", self.parse_errors);
+        metric!(
+            "typeracer_disconnects_total",
+            "counter",
+            "Disconnected clients by reason."
+        );
+        for (reason, count) in &self.disconnects {
+            let _ = writeln!(
+                out,
+                "typeracer_disconnects_total{{reason=\"{}\"}} {}",
+                reason, count
+            );
+        }
+        metric!(
+            "typeracer_races_finished_total",
+            "counter",
+            "Races that had a winner."
+        );
+        let _ = writeln!(
+            out,
+            "typeracer_races_finished_total {}",
+            self.races_finished
+        );
+        metric!(
+            "typeracer_lobbies_expired_total",
+            "counter",
+            "Lobbies closed for sitting idle."
+        );
+        let _ = writeln!(
+            out,
+            "typeracer_lobbies_expired_total {}",
+            self.lobbies_expired
+        );
+        self.race_duration.render(
+            &mut out,
+            "typeracer_race_duration_seconds",
+            "Time from the start of a race until its winner finished.",
+        );
+        self.finish_wpm.render(
+            &mut out,
+            "typeracer_finish_wpm",
+            "Words per minute of every player who finished a race.",
+        );
+        out
+    }
+}
+
+/// Biggest request we'll wait for. Nobody sends us big requests.
+const MAX_REQUEST: usize = 8192;
+/// How long a connection gets to send its request and read the response.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections served at once. Scrapers need one, anything past this is closed straight away so
+/// it can't tie up file descriptors clients need.
+const MAX_CONNECTIONS: usize = 16;
+
+struct HttpConnection {
+    stream: TcpStream,
+    read_buffer: Vec<u8>,
+    /// The response and how much of it has been written, once the request has fully arrived.
+    response: Option<(Vec<u8>, usize)>,
+    deadline: Instant,
+}
+
+impl HttpConnection {
+    /// Returns whether the request has fully arrived, or an error if the connection should be
+    /// dropped.
+    fn poll_request(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 1024];
+        loop {
+            if self.read_buffer.windows(4).any(|w| w == b"\r\n\r\n") {
+                return Ok(true);
+            }
+            if self.read_buffer.len() >= MAX_REQUEST {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Request too big",
+                ));
+            }
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.read_buffer.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Writes as much of the response as the socket takes. Returns whether all of it is out.
+    fn poll_response(&mut self) -> io::Result<bool> {
+        let (response, written) = self.response.as_mut().expect("Polled before responding");
+        while *written < response.len() {
+            match self.stream.write(&response[*written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => *written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Just enough HTTP to answer `GET /metrics`. Every response closes its connection, and
+/// connections that hang up early, send too much or take too long are dropped unanswered.
+pub struct MetricsServer {
+    listener: TcpListener,
+    connections: Vec<HttpConnection>,
+}
+
+impl MetricsServer {
+    pub fn bind(address: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(MetricsServer {
+            listener,
+            connections: vec![],
+        })
+    }
+
+    /// Answers every request that has fully arrived, calling `render` at most once.
+    pub fn poll(&mut self, now: Instant, render: impl FnOnce() -> String) {
+        match self.listener.accept() {
+            Ok((_, address)) if self.connections.len() >= MAX_CONNECTIONS => {
+                tracing::warn!(%address, "Too many metrics connections, refusing one");
+            }
+            Ok((stream, _)) => {
+                if stream.set_nonblocking(true).is_ok() {
+                    self.connections.push(HttpConnection {
+                        stream,
+                        read_buffer: vec![],
+                        response: None,
+                        deadline: now + TIMEOUT,
+                    });
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(err) => tracing::error!(%err, "Metrics accept failed"),
+        }
+        let mut body = None;
+        let mut render = Some(render);
+        self.connections.retain_mut(|connection| {
+            if now >= connection.deadline {
+                return false;
+            }
+            if connection.response.is_none() {
+                match connection.poll_request() {
+                    Ok(true) => (),
+                    Ok(false) => return true,
+                    Err(_) => return false,
+                }
+                let response = if connection.read_buffer.starts_with(b"GET /metrics ") {
+                    let body = body.get_or_insert_with(|| (render.take().unwrap())());
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                } else {
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+                };
+                connection.response = Some((response.into_bytes(), 0));
+            }
+            match connection.poll_response() {
+                Ok(true) => {
+                    let _ = connection.stream.shutdown(Shutdown::Both);
+                    false
+                }
+                Ok(false) => true,
+                Err(_) => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_count_into_the_first_bucket_that_fits() {
+        let mut histogram = Histogram::new(&[1.0, 10.0]);
+        for &value in &[0.5, 1.0, 1.5, 10.0, 11.0, 100.0] {
+            histogram.observe(value);
+        }
+        assert_eq!(histogram.counts, [2, 2, 2]);
+        let mut out = String::new();
+        histogram.render(&mut out, "latency", "How long it took.");
+        assert_eq!(
+            out,
+            "# HELP latency How long it took.\n\
+             # TYPE latency histogram\n\
+             latency_bucket{le=\"1\"} 2\n\
+             latency_bucket{le=\"10\"} 4\n\
+             latency_bucket{le=\"+Inf\"} 6\n\
+             latency_sum 124\n\
+             latency_count 6\n"
+        );
+    }
+
+    #[test]
+    fn renders_the_prometheus_text_format() {
+        let mut metrics = Metrics::new();
+        metrics.commands.insert("STATE", 3);
+        metrics.disconnects.insert("timeout", 1);
+        metrics.races_finished = 2;
+        metrics.finish_wpm.observe(75.0);
+        let lobbies = [("Racing", 1), ("WaitingForStart", 2)]
+            .iter()
+            .copied()
+            .collect();
+        let out = metrics.render(4, &lobbies);
+
+        for line in &[
+            "typeracer_connected_clients 4",
+            "typeracer_lobbies{state=\"Racing\"} 1",
+            "typeracer_lobbies{state=\"WaitingForStart\"} 2",
+            "typeracer_commands_total{command=\"STATE\"} 3",
+            "typeracer_parse_errors_total 0",
+            "typeracer_disconnects_total{reason=\"timeout\"} 1",
+            "typeracer_races_finished_total 2",
+            "typeracer_finish_wpm_bucket{le=\"60\"} 0",
+            "typeracer_finish_wpm_bucket{le=\"80\"} 1",
+            "typeracer_finish_wpm_count 1",
+        ] {
+            assert!(
+                out.lines().any(|l| l == *line),
+                "Missing {:?} in\n{}",
+                line,
+                out
+            );
+        }
+        // Every sample comes after the HELP and TYPE of its metric.
+        let mut described = vec![];
+        for line in out.lines() {
+            let mut words = line.split(' ');
+            match (words.next(), words.next(), words.next()) {
+                (Some("#"), Some("HELP"), Some(name)) => described.push(name),
+                (Some("#"), Some("TYPE"), Some(name)) => {
+                    assert_eq!(described.last(), Some(&name))
+                }
+                (Some(sample), Some(value), None) => {
+                    let name = sample.split('{').next().unwrap();
+                    assert!(
+                        described.iter().any(|&metric| name.starts_with(metric)),
+                        "{} is undescribed",
+                        name
+                    );
+                    value.parse::<f64>().unwrap();
+                }
+                _ => panic!("Malformed line {:?}", line),
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_connections_past_the_limit() {
+        let mut server = MetricsServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = server.listener.local_addr().unwrap();
+        let streams: Vec<_> = (0..MAX_CONNECTIONS + 1)
+            .map(|_| TcpStream::connect(address).unwrap())
+            .collect();
+        let now = Instant::now();
+        let deadline = now + Duration::from_secs(5);
+        while server.connections.len() < MAX_CONNECTIONS {
+            assert!(Instant::now() < deadline);
+            server.poll(now, String::new);
+        }
+        // The one left over is accepted and closed without a response.
+        let mut last = &streams[MAX_CONNECTIONS];
+        last.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        server.poll(now, String::new);
+        assert_eq!(server.connections.len(), MAX_CONNECTIONS);
+        assert_eq!(last.read(&mut [0; 16]).unwrap(), 0);
+    }
+}
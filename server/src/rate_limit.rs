@@ -0,0 +1,255 @@
+//! Per-client token buckets so one client can't flood its lobby.
+//!
+//! Commands over the limit are dropped. The first drop in a while counts as a violation and warns
+//! the client, and violations are slowly forgiven, so only a client that keeps flooding gets
+//! disconnected.
+use protocol::ClientMessage;
+use std::time::{Duration, Instant};
+
+/// Drops this soon after a warning are part of the same violation.
+const VIOLATION_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Copy, Clone)]
+pub struct RateLimit {
+    /// Tokens added per second.
+    pub rate: f64,
+    /// Maximum number of tokens the bucket holds.
+    pub burst: f64,
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            last_refill: now,
+        }
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Commands are limited separately so a chatty player can still type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CommandClass {
+    State,
+    Chat,
+    Other,
+}
+
+impl CommandClass {
+    pub fn of(message: &ClientMessage) -> Option<Self> {
+        Some(match message {
+            ClientMessage::State(_) => CommandClass::State,
+            ClientMessage::Chat(_) => CommandClass::Chat,
+            // We asked for this, don't hold it against the client.
+            ClientMessage::Pong(_) => return None,
+            _ => CommandClass::Other,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CommandClass::State => "STATE",
+            CommandClass::Chat => "CHAT",
+            CommandClass::Other => "OTHER",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct RateLimits {
+    pub state: RateLimit,
+    pub chat: RateLimit,
+    pub other: RateLimit,
+    /// How long it takes to forget one violation.
+    pub violation_decay: Duration,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Drop the command, the client was warned about this run of excess commands moments ago.
+    Throttle,
+    /// Drop the command and warn the client. This is their `n`th violation.
+    Warn(u32),
+}
+
+pub struct RateLimiter {
+    buckets: [TokenBucket; 3],
+    /// How many commands of each class we've dropped.
+    dropped: [u64; 3],
+    pub violations: u32,
+    violation_decay: Duration,
+    /// When the last violation was counted or forgiven.
+    violations_changed: Instant,
+    last_warning: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits, now: Instant) -> Self {
+        RateLimiter {
+            buckets: [
+                TokenBucket::new(limits.state, now),
+                TokenBucket::new(limits.chat, now),
+                TokenBucket::new(limits.other, now),
+            ],
+            dropped: [0; 3],
+            violations: 0,
+            violation_decay: limits.violation_decay,
+            violations_changed: now,
+            last_warning: None,
+        }
+    }
+
+    pub fn check(&mut self, class: CommandClass, now: Instant) -> Verdict {
+        if self.try_take(class, now) {
+            return Verdict::Allow;
+        }
+        self.dropped[class.index()] += 1;
+        // A client staying just over the limit has every other command dropped, which is still
+        // only one violation.
+        if let Some(warned) = self.last_warning {
+            if now.saturating_duration_since(warned) < VIOLATION_WINDOW {
+                return Verdict::Throttle;
+            }
+        }
+        self.forgive(now);
+        self.last_warning = Some(now);
+        self.violations += 1;
+        self.violations_changed = now;
+        Verdict::Warn(self.violations)
+    }
+
+    /// Takes a token without holding it against the client if there isn't one, for commands we
+    /// held back earlier.
+    pub fn try_take(&mut self, class: CommandClass, now: Instant) -> bool {
+        self.buckets[class.index()].try_take(now)
+    }
+
+    fn forgive(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.violations_changed);
+        let forgiven = match self.violation_decay.as_nanos() {
+            0 => u128::from(self.violations),
+            decay => elapsed.as_nanos() / decay,
+        };
+        let forgiven = forgiven.min(u128::from(self.violations)) as u32;
+        self.violations -= forgiven;
+        self.violations_changed = if self.violations == 0 {
+            now
+        } else {
+            self.violations_changed + self.violation_decay * forgiven
+        };
+    }
+
+    pub fn dropped(&self, class: CommandClass) -> u64 {
+        self.dropped[class.index()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn limiter(now: Instant) -> RateLimiter {
+        let limit = RateLimit {
+            rate: 10.0,
+            burst: 2.0,
+        };
+        RateLimiter::new(
+            RateLimits {
+                state: limit,
+                chat: limit,
+                other: limit,
+                violation_decay: Duration::from_secs(30),
+            },
+            now,
+        )
+    }
+
+    #[test]
+    fn buckets_refill_at_their_rate() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(
+            RateLimit {
+                rate: 10.0,
+                burst: 2.0,
+            },
+            now,
+        );
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+        assert!(!bucket.try_take(now + SECOND / 20));
+        assert!(bucket.try_take(now + SECOND / 10));
+        // Never holds more than the burst.
+        let later = now + SECOND * 10;
+        assert!(bucket.try_take(later));
+        assert!(bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+    }
+
+    #[test]
+    fn warns_once_per_run_of_drops() {
+        let now = Instant::now();
+        let mut limiter = limiter(now);
+        assert_eq!(limiter.check(CommandClass::State, now), Verdict::Allow);
+        assert_eq!(limiter.check(CommandClass::State, now), Verdict::Allow);
+        assert_eq!(limiter.check(CommandClass::State, now), Verdict::Warn(1));
+        assert_eq!(limiter.check(CommandClass::State, now), Verdict::Throttle);
+        // Classes have their own buckets.
+        assert_eq!(limiter.check(CommandClass::Chat, now), Verdict::Allow);
+        // Just over the limit, every other command gets through, and that's still one violation.
+        let mut at = now;
+        for _ in 0..9 {
+            at += SECOND / 10;
+            assert_eq!(limiter.check(CommandClass::State, at), Verdict::Allow);
+            assert_eq!(limiter.check(CommandClass::State, at), Verdict::Throttle);
+        }
+        at += SECOND / 10;
+        assert_eq!(limiter.check(CommandClass::State, at), Verdict::Allow);
+        assert_eq!(limiter.check(CommandClass::State, at), Verdict::Warn(2));
+        assert_eq!(limiter.dropped(CommandClass::State), 12);
+        assert_eq!(limiter.dropped(CommandClass::Chat), 0);
+    }
+
+    #[test]
+    fn forgives_violations_over_time() {
+        let now = Instant::now();
+        let mut limiter = limiter(now);
+        let mut flood = |at: Instant| {
+            while limiter.check(CommandClass::Other, at) == Verdict::Allow {}
+            limiter.violations
+        };
+        assert_eq!(flood(now), 1);
+        assert_eq!(flood(now + SECOND * 2), 2);
+        assert_eq!(flood(now + SECOND * 4), 3);
+        // One is forgotten every 30 seconds.
+        assert_eq!(flood(now + SECOND * 64), 2);
+        assert_eq!(flood(now + SECOND * 500), 1);
+    }
+}
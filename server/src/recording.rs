@@ -0,0 +1,409 @@
+//! Recordings of every race, from the countdown on, so they can be exported and replayed after
+//! the lobby is gone.
+//!
+//! A recording is a header followed by timestamped events. Exported, that's one JSON object per
+//! line, which is also how recordings are kept on disk when `persistence.data_dir` is set:
+//!
+//! ```text
+//! {"race":3,"lobby":"ABCDE","started_ms":1760000000000,"words":["chicken","cow"]}
+//! {"at_ms":0,"event":"name","client":1,"name":"P1"}
+//! {"at_ms":0,"event":"countdown","seconds":5}
+//! {"at_ms":5000,"event":"start"}
+//! {"at_ms":5620,"event":"state","client":1,"word":0,"character":1,"mistake":false}
+//! ```
+use anyhow::{Context, Result};
+use protocol::{ClientId, LobbyCode, PlayerState, ServerMessage};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+pub type RaceId = u64;
+
+/// How many finished races are kept in memory. Older ones can only be replayed from disk.
+const RECENT_RECORDINGS: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Who a member is, for everyone there when the countdown started and anyone who joins or
+    /// renames themselves after.
+    Name {
+        client: ClientId,
+        name: String,
+    },
+    Countdown {
+        seconds: u64,
+    },
+    Start,
+    State {
+        client: ClientId,
+        word: usize,
+        character: usize,
+        mistake: bool,
+    },
+    /// `client` typed the whole text, `place`th of everyone.
+    Finished {
+        client: ClientId,
+        place: usize,
+        time_ms: u64,
+    },
+    Disconnected {
+        client: ClientId,
+    },
+    Kicked {
+        client: ClientId,
+    },
+}
+
+impl Event {
+    pub fn state(client: ClientId, state: PlayerState) -> Self {
+        Event::State {
+            client,
+            word: state.current_word,
+            character: state.current_completed_character,
+            mistake: state.did_make_mistake,
+        }
+    }
+
+    /// What a client watching the race would have been sent.
+    fn message(&self, speed: u32) -> Option<ServerMessage> {
+        Some(match *self {
+            Event::Name { client, ref name } => ServerMessage::Name {
+                client_id: client,
+                name: name.clone(),
+            },
+            Event::Countdown { seconds } => {
+                ServerMessage::Countdown(seconds.div_ceil(u64::from(speed)))
+            }
+            Event::Start => ServerMessage::Starting,
+            Event::State {
+                client,
+                word,
+                character,
+                mistake,
+            } => ServerMessage::State {
+                client_id: client,
+                state: PlayerState {
+                    current_word: word,
+                    current_completed_character: character,
+                    did_make_mistake: mistake,
+                    ..PlayerState::default()
+                },
+            },
+            // Everyone after the winner was announced with a STATE.
+            Event::Finished {
+                client, place: 1, ..
+            } => ServerMessage::Finished(client),
+            Event::Finished { .. } => return None,
+            Event::Disconnected { client } => ServerMessage::PlayerDisconnected(client),
+            Event::Kicked { client } => ServerMessage::PlayerKicked(client),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    race: RaceId,
+    lobby: String,
+    started_ms: u64,
+    words: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Line {
+    at_ms: u64,
+    #[serde(flatten)]
+    event: Event,
+}
+
+#[derive(Clone)]
+pub struct Recording {
+    pub id: RaceId,
+    lobby: String,
+    /// Wall clock time the countdown started, in milliseconds since the epoch.
+    started_ms: u64,
+    words: Vec<String>,
+    events: Vec<(Duration, Event)>,
+    /// When the countdown started, to timestamp events against.
+    clock: Instant,
+}
+
+impl Recording {
+    pub fn new(id: RaceId, lobby: LobbyCode, words: &[String]) -> Self {
+        let started_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        Recording {
+            id,
+            lobby: lobby.to_string(),
+            started_ms,
+            words: words.to_vec(),
+            events: vec![],
+            clock: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, event: Event) {
+        self.events.push((self.clock.elapsed(), event));
+    }
+
+    /// The whole recording as JSON lines, each ending in a newline.
+    pub fn export(&self) -> String {
+        let header = Header {
+            race: self.id,
+            lobby: self.lobby.clone(),
+            started_ms: self.started_ms,
+            words: self.words.clone(),
+        };
+        let mut export = serde_json::to_string(&header).expect("Headers always serialize");
+        export.push('\n');
+        for (at, event) in &self.events {
+            let line = Line {
+                at_ms: at.as_millis() as u64,
+                event: event.clone(),
+            };
+            export += &serde_json::to_string(&line).expect("Events always serialize");
+            export.push('\n');
+        }
+        export
+    }
+
+    pub fn parse(export: &str) -> Result<Self> {
+        let mut lines = export.lines().filter(|line| !line.trim().is_empty());
+        let header: Header = serde_json::from_str(lines.next().context("Empty recording")?)
+            .context("Invalid recording header")?;
+        let events = lines
+            .map(|line| {
+                let line: Line = serde_json::from_str(line)?;
+                Ok((Duration::from_millis(line.at_ms), line.event))
+            })
+            .collect::<Result<_>>()
+            .context("Invalid recording event")?;
+        Ok(Recording {
+            id: header.race,
+            lobby: header.lobby,
+            started_ms: header.started_ms,
+            words: header.words,
+            events,
+            clock: Instant::now(),
+        })
+    }
+}
+
+/// Every finished recording, the recent ones in memory and all of them on disk if there's a
+/// data directory.
+pub struct Recordings {
+    dir: Option<PathBuf>,
+    next_id: RaceId,
+    recent: VecDeque<Arc<Recording>>,
+}
+
+impl Recordings {
+    /// Keeps recordings in `replays/` under `data_dir`, carrying on the race ids from there.
+    pub fn open(data_dir: Option<&Path>) -> Result<Self> {
+        let dir = data_dir.map(|data_dir| data_dir.join("replays"));
+        let mut last_id = 0;
+        if let Some(dir) = &dir {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path
+                    .extension()
+                    .is_some_and(|extension| extension == "jsonl")
+                {
+                    if let Some(id) = path
+                        .file_stem()
+                        .and_then(|stem| stem.to_str()?.parse::<RaceId>().ok())
+                    {
+                        last_id = last_id.max(id);
+                    }
+                }
+            }
+        }
+        Ok(Recordings {
+            dir,
+            next_id: last_id + 1,
+            recent: VecDeque::new(),
+        })
+    }
+
+    pub fn next_id(&mut self) -> RaceId {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn path(&self, id: RaceId) -> Option<PathBuf> {
+        Some(self.dir.as_ref()?.join(format!("{}.jsonl", id)))
+    }
+
+    /// Files away a race that's over.
+    pub fn archive(&mut self, recording: Recording) {
+        if let Some(path) = self.path(recording.id) {
+            match std::fs::write(&path, recording.export()) {
+                Ok(()) => info!(race = recording.id, path = %path.display(), "Saved recording"),
+                Err(err) => error!(race = recording.id, %err, "Failed to save recording"),
+            }
+        }
+        if self.recent.len() == RECENT_RECORDINGS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(Arc::new(recording));
+    }
+
+    pub fn get(&self, id: RaceId) -> Result<Option<Arc<Recording>>> {
+        if let Some(recording) = self.recent.iter().find(|recording| recording.id == id) {
+            return Ok(Some(Arc::clone(recording)));
+        }
+        let path = match self.path(id) {
+            Some(path) if path.exists() => path,
+            _ => return Ok(None),
+        };
+        let export = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(Some(Arc::new(Recording::parse(&export)?)))
+    }
+}
+
+/// Streams a recording to one client, as the messages they'd have got watching it live.
+pub struct Playback {
+    recording: Arc<Recording>,
+    /// The next event to play.
+    next: usize,
+    started: Instant,
+    speed: u32,
+}
+
+impl Playback {
+    /// Starts with the race id and text, which came before the recording did.
+    pub fn start(
+        recording: Arc<Recording>,
+        speed: u32,
+        now: Instant,
+    ) -> (Self, Vec<ServerMessage>) {
+        let messages = vec![
+            ServerMessage::Race(recording.id),
+            ServerMessage::Words(recording.words.clone()),
+        ];
+        let playback = Playback {
+            recording,
+            next: 0,
+            started: now,
+            speed,
+        };
+        (playback, messages)
+    }
+
+    pub fn race(&self) -> RaceId {
+        self.recording.id
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next >= self.recording.events.len()
+    }
+
+    /// Every message that's due by `now`.
+    pub fn tick(&mut self, now: Instant) -> Vec<ServerMessage> {
+        let elapsed = now.saturating_duration_since(self.started) * self.speed;
+        let mut messages = vec![];
+        while let Some((at, event)) = self.recording.events.get(self.next) {
+            if *at > elapsed {
+                break;
+            }
+            messages.extend(event.message(self.speed));
+            self.next += 1;
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recording() -> Recording {
+        let mut recording = Recording::new(
+            7,
+            LobbyCode::new(b"ABCDE").unwrap(),
+            &["to".to_owned(), "be".to_owned()],
+        );
+        let at = |millis| Duration::from_millis(millis);
+        recording.events = vec![
+            (
+                at(0),
+                Event::Name {
+                    client: 1,
+                    name: "ashkan".to_owned(),
+                },
+            ),
+            (at(0), Event::Countdown { seconds: 5 }),
+            (at(5000), Event::Start),
+            (
+                at(5400),
+                Event::state(
+                    1,
+                    PlayerState {
+                        current_word: 1,
+                        ..PlayerState::default()
+                    },
+                ),
+            ),
+            (
+                at(6000),
+                Event::Finished {
+                    client: 1,
+                    place: 1,
+                    time_ms: 1000,
+                },
+            ),
+            (at(7000), Event::Disconnected { client: 1 }),
+        ];
+        recording
+    }
+
+    #[test]
+    fn exports_json_lines() {
+        let recording = recording();
+        let export = recording.export();
+        assert_eq!(export.lines().count(), 7);
+        assert_eq!(
+            export.lines().nth(2).unwrap(),
+            r#"{"at_ms":0,"event":"countdown","seconds":5}"#
+        );
+        let parsed = Recording::parse(&export).unwrap();
+        assert_eq!(parsed.id, 7);
+        assert_eq!(parsed.words, recording.words);
+        assert_eq!(parsed.events, recording.events);
+        assert!(Recording::parse("").is_err());
+        assert!(Recording::parse("{\"race\":1}").is_err());
+    }
+
+    #[test]
+    fn plays_back_at_speed() {
+        let now = Instant::now();
+        let (mut playback, messages) = Playback::start(Arc::new(recording()), 2, now);
+        assert_eq!(messages[0], ServerMessage::Race(7));
+        assert_eq!(
+            playback.tick(now),
+            vec![
+                ServerMessage::Name {
+                    client_id: 1,
+                    name: "ashkan".to_owned(),
+                },
+                ServerMessage::Countdown(3),
+            ]
+        );
+        assert_eq!(playback.tick(now + Duration::from_millis(2400)), vec![]);
+        assert_eq!(
+            playback.tick(now + Duration::from_millis(2500)),
+            vec![ServerMessage::Starting]
+        );
+        assert!(!playback.is_done());
+        assert_eq!(playback.tick(now + Duration::from_secs(10)).len(), 3);
+        assert!(playback.is_done());
+    }
+}
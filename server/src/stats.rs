@@ -0,0 +1,303 @@
+//! What players' races say about their typing: which letter pairs slow them down, which keys they
+//! miss and which words take them longest.
+//!
+//! Built from the same timelines ghosts are, so every keystroke is timed by when its STATE
+//! arrived. Only finished races count. With `persistence.data_dir` set, each player's statistics
+//! are saved to `stats/` there and outlive restarts.
+use crate::ghost::Timeline;
+use anyhow::{Context, Result};
+use protocol::TypingStats;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::error;
+
+/// How many entries of each kind STATS shows.
+const SHOWN: usize = 10;
+
+#[derive(Default, Copy, Clone, Serialize, Deserialize)]
+struct Average {
+    total: Duration,
+    count: u32,
+}
+
+impl Average {
+    fn add(&mut self, time: Duration) {
+        self.total += time;
+        self.count += 1;
+    }
+
+    fn mean(&self) -> Duration {
+        self.total / self.count.max(1)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct PlayerStats {
+    races: u32,
+    /// By the two characters, as a string.
+    bigrams: HashMap<String, Average>,
+    /// Times each character was missed and times it was typed.
+    characters: HashMap<char, (u32, u32)>,
+    /// Time from finishing the word before to finishing this one, space included.
+    words: HashMap<String, Average>,
+}
+
+/// Every player's statistics, by the name they raced under.
+#[derive(Default)]
+pub struct Stats {
+    /// Where statistics are saved, one JSON file per player.
+    dir: Option<PathBuf>,
+    players: HashMap<String, PlayerStats>,
+}
+
+/// The character at `position` and where the player is once they've typed it. The last letter of
+/// a word takes them to the start of the next, since STATE doesn't count spaces.
+fn next_key(words: &[String], (word, character): (usize, usize)) -> Option<(char, (usize, usize))> {
+    let text = words.get(word)?;
+    let key = text.get(character..)?.chars().next()?;
+    let after = character + key.len_utf8();
+    Some(if after >= text.len() {
+        (key, (word + 1, 0))
+    } else {
+        (key, (word, after))
+    })
+}
+
+impl Stats {
+    /// Keeps statistics in `stats/` under `data_dir`, so they outlive restarts.
+    pub fn open(data_dir: Option<&Path>) -> Result<Self> {
+        let dir = data_dir.map(|data_dir| data_dir.join("stats"));
+        if let Some(dir) = &dir {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        Ok(Stats {
+            dir,
+            players: HashMap::new(),
+        })
+    }
+
+    /// Names can be anything, so files are named by their bytes in hex.
+    fn path(&self, player: &str) -> Option<PathBuf> {
+        let name: String = player.bytes().map(|b| format!("{:02x}", b)).collect();
+        Some(self.dir.as_ref()?.join(format!("{}.json", name)))
+    }
+
+    fn player(&mut self, player: &str) -> &mut PlayerStats {
+        let path = self.path(player);
+        self.players.entry(player.to_owned()).or_insert_with(|| {
+            let path = match path {
+                Some(path) if path.exists() => path,
+                _ => return PlayerStats::default(),
+            };
+            std::fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|contents| Ok(serde_json::from_str(&contents)?))
+                .unwrap_or_else(|err| {
+                    error!(path = %path.display(), err = ?err, "Failed to load stats");
+                    PlayerStats::default()
+                })
+        })
+    }
+
+    fn save(&self, player: &str) {
+        if let (Some(path), Some(stats)) = (self.path(player), self.players.get(player)) {
+            let contents = serde_json::to_string(stats).expect("Stats always serialize");
+            if let Err(err) = std::fs::write(&path, contents) {
+                error!(path = %path.display(), %err, "Failed to save stats");
+            }
+        }
+    }
+
+    /// Adds a finished race on `words`.
+    pub fn record(&mut self, player: &str, words: &[String], timeline: &Timeline) {
+        let stats = self.player(player);
+        stats.races += 1;
+        let mut position = (0, 0);
+        let mut mistake = false;
+        // The last key typed on its own, and when, to time the one after it.
+        let mut last_key: Option<(char, Duration)> = None;
+        // The word being typed and when the one before it was finished.
+        let mut word_started: Option<(usize, Duration)> = None;
+        for &(at, state) in timeline {
+            let now = (state.current_word, state.current_completed_character);
+            if state.did_make_mistake && !mistake {
+                if let Some((key, _)) = next_key(words, now) {
+                    stats.characters.entry(key).or_default().0 += 1;
+                }
+            }
+            mistake = state.did_make_mistake;
+
+            // Updates can skip keys when they're sent in a hurry, which leaves those untimed.
+            let mut keys = 0;
+            let mut key = None;
+            while position < now {
+                match next_key(words, position) {
+                    Some((typed, after)) => {
+                        stats.characters.entry(typed).or_default().1 += 1;
+                        key = Some((typed, position.1 > 0));
+                        position = after;
+                        keys += 1;
+                    }
+                    None => break,
+                }
+            }
+            match (keys, key, last_key) {
+                (0, _, _) => continue,
+                // Pairs across a space are timed with the space, so only count the ones in words.
+                (1, Some((second, true)), Some((first, then))) => {
+                    stats
+                        .bigrams
+                        .entry([first, second].iter().collect())
+                        .or_default()
+                        .add(at - then);
+                }
+                _ => (),
+            }
+            last_key = match (keys, key) {
+                (1, Some((typed, _))) => Some((typed, at)),
+                _ => None,
+            };
+
+            if let Some((word, started)) = word_started {
+                if now == (word + 1, 0) {
+                    stats
+                        .words
+                        .entry(words[word].clone())
+                        .or_default()
+                        .add(at - started);
+                }
+            }
+            // The first word is timed from the start of the race, which would count how long
+            // they took to react.
+            word_started = match now {
+                (word, 0) if word > 0 => Some((word, at)),
+                _ => word_started.filter(|&(word, _)| word == now.0),
+            };
+        }
+        self.save(player);
+    }
+
+    /// `player`'s slowest bigrams and words, and most missed characters.
+    pub fn report(&mut self, player: &str) -> TypingStats {
+        let stats = self.player(player);
+        let mut bigrams: Vec<_> = stats
+            .bigrams
+            .iter()
+            .map(|(bigram, average)| (bigram.clone(), average.mean().as_millis() as u64))
+            .collect();
+        bigrams.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        bigrams.truncate(SHOWN);
+
+        let mut characters: Vec<_> = stats
+            .characters
+            .iter()
+            .filter(|(_, &(missed, _))| missed > 0)
+            .map(|(&character, &(missed, typed))| (character, missed, typed))
+            .collect();
+        // Worst miss rate first. Compared as fractions so it stays exact.
+        characters.sort_by(|a, b| {
+            (u64::from(b.1) * u64::from(a.2.max(1)))
+                .cmp(&(u64::from(a.1) * u64::from(b.2.max(1))))
+                .then_with(|| b.1.cmp(&a.1))
+                .then_with(|| a.0.cmp(&b.0))
+        });
+        characters.truncate(SHOWN);
+
+        let mut words: Vec<_> = stats
+            .words
+            .iter()
+            .map(|(word, average)| {
+                // One "word" is 5 characters, and the space before it is typed too.
+                let minutes = average.mean().as_secs_f64() / 60.0;
+                let wpm = (word.len() + 1) as f64 / 5.0 / minutes;
+                (word.clone(), wpm.round() as u32)
+            })
+            .collect();
+        words.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        words.truncate(SHOWN);
+
+        TypingStats {
+            races: stats.races,
+            bigrams,
+            characters,
+            words,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::PlayerState;
+
+    fn text(words: &[&str]) -> Vec<String> {
+        words.iter().map(|&word| word.to_owned()).collect()
+    }
+
+    fn at(millis: u64, word: usize, character: usize, mistake: bool) -> (Duration, PlayerState) {
+        (
+            Duration::from_millis(millis),
+            PlayerState {
+                current_word: word,
+                current_completed_character: character,
+                did_make_mistake: mistake,
+                ..PlayerState::default()
+            },
+        )
+    }
+
+    #[test]
+    fn times_keys_and_words() {
+        let words = text(&["ox", "to", "tot"]);
+        let timeline = vec![
+            at(500, 0, 1, false),
+            at(700, 1, 0, false),
+            // Missed the t, then typed it and the o in one update.
+            at(1000, 1, 0, true),
+            at(1100, 2, 0, false),
+            at(1400, 2, 1, false),
+            at(1500, 2, 2, false),
+            at(1800, 3, 0, false),
+        ];
+        let mut stats = Stats::default();
+        stats.record("ashkan", &words, &timeline);
+        stats.record("ashkan", &words, &timeline);
+        let report = stats.report("ashkan");
+        assert_eq!(report.races, 2);
+        assert_eq!(
+            report.bigrams,
+            [
+                ("ot".to_owned(), 300),
+                ("ox".to_owned(), 200),
+                ("to".to_owned(), 100)
+            ]
+        );
+        assert_eq!(report.characters, [('t', 2, 6)]);
+        // "to" and its space in 400ms, "tot" and its space in 700ms.
+        assert_eq!(
+            report.words,
+            [("tot".to_owned(), 69), ("to".to_owned(), 90)]
+        );
+        assert_eq!(stats.report("norcalli"), TypingStats::default());
+    }
+
+    #[test]
+    fn outlive_restarts() {
+        let dir = std::env::temp_dir().join(format!("typeracer-stats-{}", std::process::id()));
+        let words = text(&["to", "be"]);
+        let timeline = vec![
+            at(500, 0, 1, false),
+            at(700, 1, 0, true),
+            at(900, 2, 0, false),
+        ];
+        let mut stats = Stats::open(Some(&dir)).unwrap();
+        stats.record("ashkan", &words, &timeline);
+        let report = stats.report("ashkan");
+        assert_eq!(report.races, 1);
+        assert_eq!(Stats::open(Some(&dir)).unwrap().report("ashkan"), report);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
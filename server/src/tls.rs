@@ -0,0 +1,262 @@
+//! Optional TLS on the game listener.
+//!
+//! Clients are served from the same nonblocking poll loop either way, so `Stream` hides whether a
+//! client connected over plaintext or TLS.
+use anyhow::{Context, Result};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection};
+use std::io::{self, prelude::*};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Reads a PEM certificate chain and private key, as produced by e.g. certbot.
+pub fn load_server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read TLS certificate {}", cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Failed to read TLS key {}", key.display()))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")?;
+    Ok(Arc::new(config))
+}
+
+pub struct TlsStream {
+    socket: TcpStream,
+    connection: ServerConnection,
+}
+
+impl TlsStream {
+    /// Sends whatever TLS records are queued up, as far as the socket lets us. Anything left
+    /// over goes out on the next read or write.
+    fn send_records(&mut self) -> io::Result<()> {
+        while self.connection.wants_write() {
+            match self.connection.write_tls(&mut self.socket) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+                Ok(_) => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.connection.reader().read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                // Plenty of clients hang up without a close_notify, treat it like any other EOF.
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                result => return result,
+            }
+            // Handshake messages and anything written before the handshake finished.
+            self.send_records()?;
+            // Returns WouldBlock once the socket is drained.
+            self.connection.read_tls(&mut self.socket)?;
+            if let Err(err) = self.connection.process_new_packets() {
+                // Try to tell the peer what went wrong before we drop them.
+                let _ = self.send_records();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    /// Plaintext is buffered by rustls until the handshake is done, so writing to a fresh
+    /// connection is fine.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.connection.writer().write(buf)?;
+        self.send_records()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.connection.writer().flush()?;
+        self.send_records()
+    }
+}
+
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream>),
+}
+
+impl Stream {
+    /// Starts a server side TLS session on an accepted socket. The handshake happens as we read.
+    pub fn tls(socket: TcpStream, config: Arc<ServerConfig>) -> Result<Self> {
+        let connection = ServerConnection::new(config)?;
+        Ok(Stream::Tls(Box::new(TlsStream { socket, connection })))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Stream::Tls(_))
+    }
+
+    fn socket(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(socket) => socket,
+            Stream::Tls(stream) => &stream.socket,
+        }
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.socket().take_error()
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        if let Stream::Tls(stream) = self {
+            stream.connection.send_close_notify();
+            stream.send_records()?;
+        }
+        self.socket().shutdown(how)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use std::convert::TryFrom;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    /// Writes a fresh self-signed certificate for localhost and returns its DER too, so the
+    /// client can trust it.
+    fn self_signed(name: &str) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        let rcgen::CertifiedKey { cert, signing_key } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("typeracer-{}-{}.crt", name, std::process::id()));
+        let key_path = dir.join(format!("typeracer-{}-{}.key", name, std::process::id()));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, signing_key.serialize_pem()).unwrap();
+        (cert_path, key_path, cert.der().clone())
+    }
+
+    /// Reads from the nonblocking server side until a full line has arrived.
+    fn read_line(stream: &mut Stream) -> Vec<u8> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut buffer = vec![];
+        while !buffer.ends_with(b"\n") {
+            assert!(Instant::now() < deadline, "Timed out reading {:?}", buffer);
+            match stream.read_to_end(&mut buffer) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(1))
+                }
+                Err(err) => panic!("{}", err),
+                Ok(_) => break,
+            }
+        }
+        buffer
+    }
+
+    #[test]
+    fn serves_tls_clients_from_a_nonblocking_socket() {
+        let (cert_path, key_path, cert) = self_signed("serves");
+        let config = load_server_config(&cert_path, &key_path).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut roots = RootCertStore::empty();
+            roots.add(cert).unwrap();
+            let config = ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let connection =
+                ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap())
+                    .unwrap();
+            let mut stream = StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+            let mut line = String::new();
+            io::BufReader::new(&mut stream)
+                .read_line(&mut line)
+                .unwrap();
+            stream.write_all(b"CREATE\n").unwrap();
+            stream.conn.send_close_notify();
+            stream.flush().unwrap();
+            line
+        });
+
+        let (socket, _) = listener.accept().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let mut stream = Stream::tls(socket, config).unwrap();
+        assert!(stream.is_tls());
+        // Written before the handshake, like CONNECTED is.
+        stream.write_all(b"CONNECTED 1\n").unwrap();
+        assert_eq!(read_line(&mut stream), b"CREATE\n");
+        assert_eq!(client.join().unwrap(), "CONNECTED 1\n");
+
+        let _ = std::fs::remove_file(cert_path);
+        let _ = std::fs::remove_file(key_path);
+    }
+
+    #[test]
+    fn drops_clients_that_dont_speak_tls() {
+        let (cert_path, key_path, _) = self_signed("plaintext");
+        let config = load_server_config(&cert_path, &key_path).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"CREATE\n").unwrap();
+
+        let (socket, _) = listener.accept().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let mut stream = Stream::tls(socket, config).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let err = loop {
+            assert!(
+                Instant::now() < deadline,
+                "Plaintext client wasn't rejected"
+            );
+            match stream.read(&mut [0; 64]) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(1))
+                }
+                Err(err) => break err,
+                Ok(n) => panic!("Read {} bytes of plaintext", n),
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let _ = std::fs::remove_file(cert_path);
+        let _ = std::fs::remove_file(key_path);
+    }
+
+    #[test]
+    fn rejects_missing_certificates() {
+        let missing = Path::new("/nonexistent/typeracer.pem");
+        assert!(load_server_config(missing, missing).is_err());
+    }
+}
@@ -36,6 +36,8 @@ client:connect("127.0.0.1", 1234, function(err)
         print(message)
         client:write(message)
       end)
+    elseif line:match("^PING ") then
+      client:write("PONG "..line:match("PING (.*)").."\n")
     elseif line:match("^WORDS ") then
       local tail = line:match("WORDS (.*)")
       words = splitter("%s+").collect(tail)
@@ -0,0 +1,921 @@
+//! Boots a server in process and drives scripted clients through whole races, checking every
+//! message each of them gets back.
+use protocol::{ClientId, ClientMessage, LobbyCode, PlayerState, ServerMessage};
+use server::config::{Config, SuccessionPolicy};
+use server::Server;
+use std::io::{prelude::*, BufReader};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const SHUTDOWN_GRACE: u64 = 30;
+
+fn test_config() -> Config {
+    let mut config = Config::default();
+    config.network.address = "127.0.0.1".to_owned();
+    config.network.port = 0;
+    // Keep PINGs out of the scripts.
+    config.network.ping_interval = 3600;
+    config.network.ping_timeout = 3600;
+    config.network.shutdown_grace = SHUTDOWN_GRACE;
+    config.lobby.countdown = 1;
+    config.lobby.word_count = 3;
+    config
+}
+
+/// Why binding a server on `admin_socket` fails.
+fn admin_bind_error(admin_socket: &Path) -> String {
+    let mut config = test_config();
+    config.network.admin_socket = Some(admin_socket.to_owned());
+    match Server::bind(config) {
+        Ok(_) => panic!("Bound {}", admin_socket.display()),
+        Err(err) => format!("{:#}", err),
+    }
+}
+
+struct TestServer {
+    address: SocketAddr,
+    terminate: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl TestServer {
+    fn start() -> Self {
+        Self::start_with(|_| ())
+    }
+
+    /// Starts a server with the test settings, adjusted by `configure`.
+    fn start_with(configure: impl FnOnce(&mut Config)) -> Self {
+        let mut config = test_config();
+        configure(&mut config);
+        let server = Server::bind(config).unwrap();
+        let address = server.local_addr().unwrap();
+        let terminate = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let terminate = Arc::clone(&terminate);
+            std::thread::spawn(move || server.run(terminate).unwrap())
+        };
+        TestServer {
+            address,
+            terminate,
+            thread,
+        }
+    }
+
+    fn connect(&self, id: ClientId) -> TestClient {
+        let stream = TcpStream::connect(self.address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut client = TestClient {
+            id,
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        };
+        client.expect(&[ServerMessage::Connected(id)]);
+        client
+    }
+
+    /// Like a SIGTERM, waits for the server to exit.
+    fn shut_down(self) {
+        self.terminate.store(true, Ordering::Relaxed);
+        self.thread.join().unwrap();
+    }
+}
+
+struct TestClient {
+    id: ClientId,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl TestClient {
+    fn send(&mut self, message: ClientMessage) {
+        self.writer.write_all(message.encode().as_bytes()).unwrap();
+    }
+
+    fn recv(&mut self) -> ServerMessage {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap_or_else(|err| {
+            panic!("Client {} didn't get a message: {}", self.id, err);
+        });
+        assert!(!line.is_empty(), "Client {} was disconnected", self.id);
+        ServerMessage::decode(line.as_bytes()).unwrap()
+    }
+
+    fn expect(&mut self, expected: &[ServerMessage]) {
+        let received: Vec<_> = expected.iter().map(|_| self.recv()).collect();
+        assert_eq!(received, expected, "Client {}", self.id);
+    }
+
+    fn expect_words(&mut self) -> Vec<String> {
+        match self.recv() {
+            ServerMessage::Words(words) => words,
+            message => panic!("Client {} expected WORDS, got {:?}", self.id, message),
+        }
+    }
+
+    fn expect_accuracy(&mut self, client_id: ClientId) {
+        match self.recv() {
+            ServerMessage::Accuracy { client_id: id, .. } if id == client_id => (),
+            message => panic!("Client {} expected ACCURACY, got {:?}", self.id, message),
+        }
+    }
+
+    /// Creates a lobby and returns its code and words.
+    fn create_lobby(&mut self) -> (LobbyCode, Vec<String>) {
+        self.send(ClientMessage::Create);
+        let code = match self.recv() {
+            ServerMessage::Created(code) => code,
+            message => panic!("Client {} expected CREATED, got {:?}", self.id, message),
+        };
+        let words = self.expect_words();
+        self.expect(&[state(self.id, 0, false)]);
+        (code, words)
+    }
+
+    /// Starts a race as the lobby's only player.
+    fn start_race(&mut self, race_id: u64) {
+        self.send(ClientMessage::Start);
+        self.expect_race_start(race_id);
+    }
+
+    fn expect_race_start(&mut self, race_id: u64) {
+        self.expect(&[
+            ServerMessage::Countdown(1),
+            ServerMessage::Starting,
+            ServerMessage::Race(race_id),
+        ]);
+    }
+
+    fn expect_closed(&mut self) {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "", "Client {}", self.id);
+    }
+}
+
+fn state(client_id: ClientId, current_word: usize, mistake: bool) -> ServerMessage {
+    ServerMessage::State {
+        client_id,
+        state: PlayerState {
+            current_word,
+            current_completed_character: 0,
+            did_make_mistake: mistake,
+            ..PlayerState::default()
+        },
+    }
+}
+
+/// What a player who never made a mistake gets told when they finish.
+fn accurate(client_id: ClientId) -> ServerMessage {
+    ServerMessage::Accuracy {
+        client_id,
+        words: Vec::new(),
+    }
+}
+
+fn typed(current_word: usize, mistake: bool) -> ClientMessage {
+    ClientMessage::State(PlayerState {
+        current_word,
+        current_completed_character: 0,
+        did_make_mistake: mistake,
+        ..PlayerState::default()
+    })
+}
+
+#[test]
+fn full_race() {
+    let server = TestServer::start();
+    let mut leader = server.connect(1);
+    let mut joiner = server.connect(2);
+    let mut random = server.connect(3);
+
+    let (code, words) = leader.create_lobby();
+    assert_eq!(words.len(), 3);
+
+    // Codes can be typed in any case.
+    let typed_code = code.as_str().to_ascii_lowercase();
+    joiner.send(ClientMessage::Join(
+        LobbyCode::new(typed_code.as_bytes()).unwrap(),
+    ));
+    joiner.expect(&[ServerMessage::Joined(code)]);
+    assert_eq!(joiner.expect_words(), words);
+    joiner.expect(&[state(1, 0, false), state(2, 0, false)]);
+    leader.expect(&[state(1, 0, false), state(2, 0, false)]);
+
+    random.send(ClientMessage::JoinRandom);
+    random.expect(&[ServerMessage::Joined(code)]);
+    assert_eq!(random.expect_words(), words);
+    let everyone = [state(1, 0, false), state(2, 0, false), state(3, 0, false)];
+    for client in &mut [&mut leader, &mut joiner, &mut random] {
+        client.expect(&everyone);
+    }
+
+    leader.send(ClientMessage::Start);
+    for client in &mut [&mut leader, &mut joiner, &mut random] {
+        client.expect_race_start(1);
+    }
+
+    joiner.send(typed(1, true));
+    for client in &mut [&mut leader, &mut joiner, &mut random] {
+        client.expect(&[state(2, 1, true)]);
+    }
+    for word in 1..words.len() {
+        leader.send(typed(word, false));
+        for client in &mut [&mut leader, &mut joiner, &mut random] {
+            client.expect(&[state(1, word, false)]);
+        }
+    }
+    leader.send(typed(words.len(), false));
+    for client in &mut [&mut leader, &mut joiner, &mut random] {
+        client.expect(&[ServerMessage::Finished(1), accurate(1)]);
+    }
+    // The others can still finish after the winner.
+    joiner.send(typed(words.len(), false));
+    for client in &mut [&mut leader, &mut joiner, &mut random] {
+        client.expect(&[state(2, words.len(), false), accurate(2)]);
+    }
+
+    // With the race won there's nothing to wait for.
+    server.shut_down();
+    for client in &mut [&mut leader, &mut joiner, &mut random] {
+        client.expect(&[ServerMessage::ServerShutdown(SHUTDOWN_GRACE)]);
+        client.expect_closed();
+    }
+}
+
+#[test]
+fn fast_clients_are_throttled_not_dropped() {
+    // Like test-client.lua, a key every 10ms, well over the default STATE limit.
+    let server = TestServer::start_with(|config| config.lobby.word_count = 30);
+    let mut racer = server.connect(1);
+    let (_, words) = racer.create_lobby();
+    racer.start_race(1);
+    for (word, text) in words.iter().enumerate() {
+        for character in 1..=text.len() {
+            let (word, character) = match character {
+                end if end == text.len() => (word + 1, 0),
+                character => (word, character),
+            };
+            racer.send(ClientMessage::State(PlayerState {
+                current_word: word,
+                current_completed_character: character,
+                ..PlayerState::default()
+            }));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+    // Dropped updates are skipped over, but the last one always gets through.
+    let mut warnings = 0;
+    loop {
+        match racer.recv() {
+            ServerMessage::State { client_id: 1, .. } => (),
+            ServerMessage::Throttled { .. } => warnings += 1,
+            ServerMessage::Finished(1) => break,
+            message => panic!("Got {:?}", message),
+        }
+    }
+    racer.expect(&[accurate(1)]);
+    assert!((1..=3).contains(&warnings), "{} warnings", warnings);
+
+    server.shut_down();
+    racer.expect(&[ServerMessage::ServerShutdown(SHUTDOWN_GRACE)]);
+    racer.expect_closed();
+}
+
+#[test]
+fn kicked_and_banned_players_are_told_and_kept_out() {
+    let server = TestServer::start();
+    let mut leader = server.connect(1);
+    let mut player = server.connect(2);
+    let (code, _) = leader.create_lobby();
+    let join = |player: &mut TestClient, leader: &mut TestClient| {
+        player.send(ClientMessage::Join(code));
+        player.expect(&[ServerMessage::Joined(code)]);
+        player.expect_words();
+        player.expect(&[state(1, 0, false), state(2, 0, false)]);
+        leader.expect(&[state(1, 0, false), state(2, 0, false)]);
+    };
+    join(&mut player, &mut leader);
+
+    // Kicked players can come back.
+    leader.send(ClientMessage::Kick(2));
+    player.expect(&[ServerMessage::Kicked(
+        "Removed by the lobby leader".to_owned(),
+    )]);
+    leader.expect(&[ServerMessage::PlayerKicked(2)]);
+    join(&mut player, &mut leader);
+
+    leader.send(ClientMessage::Ban(2));
+    player.expect(&[ServerMessage::Kicked(
+        "Banned by the lobby leader".to_owned(),
+    )]);
+    leader.expect(&[ServerMessage::PlayerKicked(2)]);
+    player.send(ClientMessage::Join(code));
+    player.expect(&[ServerMessage::JoinFailed { banned: true }]);
+    // Bans go by address, so they keep out new connections from it too.
+    let mut neighbour = server.connect(3);
+    neighbour.send(ClientMessage::Join(code));
+    neighbour.expect(&[ServerMessage::JoinFailed { banned: true }]);
+
+    server.shut_down();
+    for client in &mut [&mut leader, &mut player, &mut neighbour] {
+        client.expect(&[ServerMessage::ServerShutdown(SHUTDOWN_GRACE)]);
+        client.expect_closed();
+    }
+}
+
+#[test]
+fn joining_a_missing_lobby_fails() {
+    let server = TestServer::start();
+    let mut client = server.connect(1);
+    client.send(ClientMessage::Join(LobbyCode::new(b"NOPE").unwrap()));
+    client.expect(&[ServerMessage::JoinFailed { banned: false }]);
+    client.send(ClientMessage::JoinRandom);
+    client.expect(&[ServerMessage::JoinFailed { banned: false }]);
+
+    server.shut_down();
+    client.expect(&[ServerMessage::ServerShutdown(SHUTDOWN_GRACE)]);
+    client.expect_closed();
+}
+
+#[test]
+fn bots_race_like_players() {
+    let server = TestServer::start();
+    let mut leader = server.connect(1);
+    let (code, words) = leader.create_lobby();
+
+    // Bots take the next client id.
+    leader.send(ClientMessage::AddBot {
+        wpm: 300,
+        variance: None,
+    });
+    leader.expect(&[
+        ServerMessage::Name {
+            client_id: 2,
+            name: "Bot300".to_owned(),
+        },
+        state(2, 0, false),
+    ]);
+    let mut joiner = server.connect(3);
+    joiner.send(ClientMessage::Join(code));
+    joiner.expect(&[
+        ServerMessage::Joined(code),
+        ServerMessage::Name {
+            client_id: 2,
+            name: "Bot300".to_owned(),
+        },
+        state(2, 0, false),
+    ]);
+    assert_eq!(joiner.expect_words(), words);
+    joiner.expect(&[state(1, 0, false), state(3, 0, false)]);
+    leader.expect(&[state(1, 0, false), state(3, 0, false)]);
+
+    leader.send(ClientMessage::Start);
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect_race_start(1);
+        let mut progress = (0, 0);
+        loop {
+            match client.recv() {
+                ServerMessage::State {
+                    client_id: 2,
+                    state,
+                } => {
+                    let now = (state.current_word, state.current_completed_character);
+                    assert!(now >= progress, "{:?} went back to {:?}", progress, now);
+                    progress = now;
+                }
+                ServerMessage::Finished(2) => break,
+                message => panic!("Client {} got {:?}", client.id, message),
+            }
+        }
+        client.expect_accuracy(2);
+    }
+
+    server.shut_down();
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect(&[ServerMessage::ServerShutdown(SHUTDOWN_GRACE)]);
+        client.expect_closed();
+    }
+}
+
+#[test]
+fn ghosts_replay_personal_bests() {
+    let server = TestServer::start();
+    let mut leader = server.connect(1);
+    let (_, words) = leader.create_lobby();
+    // Nothing to replay before the first race.
+    leader.send(ClientMessage::AddGhost { record: false });
+    leader.expect(&[ServerMessage::NoGhost]);
+
+    leader.start_race(1);
+    for word in 1..words.len() {
+        leader.send(typed(word, false));
+        leader.expect(&[state(1, word, false)]);
+    }
+    leader.send(typed(words.len(), false));
+    leader.expect(&[ServerMessage::Finished(1), accurate(1)]);
+    // Whole words at a time leave no keys to time on their own.
+    leader.send(ClientMessage::Stats);
+    match leader.recv() {
+        ServerMessage::Stats(stats) => {
+            assert_eq!(stats.races, 1);
+            assert!(stats.bigrams.is_empty(), "{:?}", stats);
+            assert!(stats.characters.is_empty(), "{:?}", stats);
+        }
+        message => panic!("Expected STATS, got {:?}", message),
+    }
+
+    leader.send(ClientMessage::AddGhost { record: true });
+    leader.expect(&[
+        ServerMessage::Name {
+            client_id: 2,
+            name: "P1(ghost)".to_owned(),
+        },
+        state(2, 0, false),
+    ]);
+    leader.start_race(2);
+    // The ghost types exactly what we did, and wins since we sit this one out.
+    let mut progress = 0;
+    loop {
+        match leader.recv() {
+            ServerMessage::State {
+                client_id: 2,
+                state,
+            } => {
+                assert!(state.current_word > progress, "{:?}", state);
+                progress = state.current_word;
+            }
+            ServerMessage::Finished(2) => break,
+            message => panic!("Got {:?}", message),
+        }
+    }
+    leader.expect_accuracy(2);
+
+    server.shut_down();
+    leader.expect(&[ServerMessage::ServerShutdown(SHUTDOWN_GRACE)]);
+    leader.expect_closed();
+}
+
+#[test]
+fn replays_recorded_races() {
+    let server = TestServer::start();
+    let mut leader = server.connect(1);
+    let mut viewer = server.connect(2);
+    let (_, words) = leader.create_lobby();
+    leader.start_race(1);
+    for word in 1..words.len() {
+        leader.send(typed(word, false));
+        leader.expect(&[state(1, word, false)]);
+    }
+    leader.send(typed(words.len(), false));
+    leader.expect(&[ServerMessage::Finished(1), accurate(1)]);
+
+    viewer.send(ClientMessage::Replay {
+        race: 9,
+        speed: None,
+    });
+    viewer.expect(&[ServerMessage::NoReplay(9)]);
+    viewer.send(ClientMessage::Replay {
+        race: 1,
+        speed: Some(4),
+    });
+    viewer.expect(&[ServerMessage::Race(1), ServerMessage::Words(words.clone())]);
+    viewer.expect(&[
+        ServerMessage::Name {
+            client_id: 1,
+            name: "P1".to_owned(),
+        },
+        ServerMessage::Countdown(1),
+        ServerMessage::Starting,
+    ]);
+    for word in 1..words.len() {
+        viewer.expect(&[state(1, word, false)]);
+    }
+    viewer.expect(&[ServerMessage::Finished(1), ServerMessage::ReplayEnd(1)]);
+
+    server.shut_down();
+    for client in &mut [&mut leader, &mut viewer] {
+        client.expect(&[ServerMessage::ServerShutdown(SHUTDOWN_GRACE)]);
+        client.expect_closed();
+    }
+}
+
+#[test]
+fn daily_challenge_counts_the_first_race() {
+    let server = TestServer::start();
+    let mut racer = server.connect(1);
+    let mut other = server.connect(2);
+    racer.send(ClientMessage::Name("ashkan".to_owned()));
+    let mut words = vec![];
+    let mut date = String::new();
+    for (id, client) in &mut [(1, &mut racer), (2, &mut other)] {
+        client.send(ClientMessage::Daily);
+        match client.recv() {
+            ServerMessage::Created(_) => (),
+            message => panic!("Expected CREATED, got {:?}", message),
+        }
+        date = match client.recv() {
+            ServerMessage::Daily(date) => date,
+            message => panic!("Expected DAILY, got {:?}", message),
+        };
+        // Every lobby racing the daily gets the same text.
+        let daily_words = client.expect_words();
+        assert!(words.is_empty() || words == daily_words);
+        words = daily_words;
+        client.expect(&[state(*id, 0, false)]);
+    }
+
+    let race = |racer: &mut TestClient, race_id| {
+        let id = racer.id;
+        racer.start_race(race_id);
+        for word in 1..words.len() {
+            racer.send(typed(word, false));
+            racer.expect(&[state(id, word, false)]);
+        }
+        racer.send(typed(words.len(), false));
+        racer.expect(&[ServerMessage::Finished(id), accurate(id)]);
+    };
+    race(&mut racer, 1);
+    // Practice doesn't go on the board, under another name or from another connection either.
+    racer.send(ClientMessage::Name("hsakna".to_owned()));
+    racer.expect(&[ServerMessage::Name {
+        client_id: 1,
+        name: "hsakna".to_owned(),
+    }]);
+    race(&mut racer, 2);
+    race(&mut other, 3);
+    other.send(ClientMessage::DailyBoard);
+    match other.recv() {
+        ServerMessage::DailyBoard {
+            date: board_date,
+            finishes,
+        } => {
+            assert_eq!(board_date, date);
+            assert_eq!(finishes.len(), 1);
+            assert_eq!(finishes[0].0, "ashkan");
+        }
+        message => panic!("Expected DAILY_BOARD, got {:?}", message),
+    }
+
+    server.shut_down();
+    for client in &mut [&mut racer, &mut other] {
+        client.expect(&[ServerMessage::ServerShutdown(SHUTDOWN_GRACE)]);
+        client.expect_closed();
+    }
+}
+
+#[test]
+fn seeded_servers_repeat_themselves() {
+    let session = || {
+        let server = TestServer::start_with(|config| config.lobby.seed = Some(42));
+        let mut lobbies = vec![];
+        let mut clients = vec![];
+        for id in 1..=3 {
+            let mut client = server.connect(id);
+            lobbies.push(client.create_lobby());
+            clients.push(client);
+        }
+        let mut joiner = server.connect(4);
+        joiner.send(ClientMessage::JoinRandom);
+        let joined = match joiner.recv() {
+            ServerMessage::Joined(code) => code,
+            message => panic!("Expected JOINED, got {:?}", message),
+        };
+        server.shut_down();
+        (lobbies, joined)
+    };
+    let (lobbies, joined) = session();
+    assert_eq!(session(), (lobbies.clone(), joined));
+    // Not just the same lobby over and over.
+    assert_ne!(lobbies[0], lobbies[1]);
+}
+
+#[test]
+fn idle_lobbies_expire() {
+    let server = TestServer::start_with(|config| {
+        config.lobby.waiting_timeout = 2;
+        config.lobby.race_idle_timeout = 2;
+        config.lobby.post_race_timeout = 2;
+        config.lobby.expiry_warning = 1;
+    });
+    let mut leader = server.connect(1);
+    let expired = [ServerMessage::LobbyExpiring(1), ServerMessage::LobbyClosed];
+
+    // Nobody starts it.
+    let (code, _) = leader.create_lobby();
+    leader.expect(&expired);
+    let mut joiner = server.connect(2);
+    joiner.send(ClientMessage::Join(code));
+    joiner.expect(&[ServerMessage::JoinFailed { banned: false }]);
+
+    // Nobody types.
+    leader.create_lobby();
+    leader.start_race(1);
+    leader.send(typed(1, false));
+    leader.expect(&[state(1, 1, false)]);
+    leader.expect(&expired);
+
+    // Nobody starts another race.
+    let (_, words) = leader.create_lobby();
+    leader.start_race(2);
+    leader.send(typed(words.len(), false));
+    leader.expect(&[ServerMessage::Finished(1), accurate(1)]);
+    leader.expect(&expired);
+
+    server.shut_down();
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect(&[ServerMessage::ServerShutdown(SHUTDOWN_GRACE)]);
+        client.expect_closed();
+    }
+}
+
+#[test]
+fn races_are_restarted_not_started_over() {
+    let server = TestServer::start();
+    let mut leader = server.connect(1);
+    let mut joiner = server.connect(2);
+
+    let (code, words) = leader.create_lobby();
+    joiner.send(ClientMessage::Join(code));
+    joiner.expect(&[ServerMessage::Joined(code)]);
+    joiner.expect_words();
+    joiner.expect(&[state(1, 0, false), state(2, 0, false)]);
+    leader.expect(&[state(1, 0, false), state(2, 0, false)]);
+
+    // Keystrokes before the race are dropped rather than shown to anyone.
+    joiner.send(typed(1, false));
+    leader.send(ClientMessage::Start);
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect_race_start(1);
+    }
+
+    // A race that's running can't be started again, or restarted before it's won.
+    leader.send(ClientMessage::Start);
+    leader.send(ClientMessage::Restart);
+    leader.expect(&[
+        ServerMessage::Invalid {
+            command: "START".to_owned(),
+            state: "RaceRunning".to_owned(),
+        },
+        ServerMessage::Invalid {
+            command: "RESTART".to_owned(),
+            state: "RaceRunning".to_owned(),
+        },
+    ]);
+
+    leader.send(typed(words.len(), false));
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect(&[ServerMessage::Finished(1), accurate(1)]);
+    }
+    leader.send(ClientMessage::Restart);
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect(&[state(1, 0, false), state(2, 0, false)]);
+    }
+    leader.send(ClientMessage::Start);
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect_race_start(2);
+    }
+    joiner.send(typed(words.len(), false));
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect(&[ServerMessage::Finished(2), accurate(2)]);
+    }
+
+    server.shut_down();
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect(&[ServerMessage::ServerShutdown(SHUTDOWN_GRACE)]);
+        client.expect_closed();
+    }
+}
+
+#[test]
+fn switching_to_the_daily_restarts_a_finished_race() {
+    let server = TestServer::start();
+    let mut leader = server.connect(1);
+    let mut joiner = server.connect(2);
+    let (code, words) = leader.create_lobby();
+    joiner.send(ClientMessage::Join(code));
+    joiner.expect(&[ServerMessage::Joined(code)]);
+    joiner.expect_words();
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect(&[state(1, 0, false), state(2, 0, false)]);
+    }
+    leader.start_race(1);
+    joiner.expect_race_start(1);
+    joiner.send(typed(1, false));
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect(&[state(2, 1, false)]);
+    }
+    leader.send(typed(words.len(), false));
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect(&[ServerMessage::Finished(1), accurate(1)]);
+    }
+
+    // The joiner is still typing the old text when it changes.
+    leader.send(ClientMessage::Daily);
+    let mut daily = vec![];
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect(&[state(1, 0, false), state(2, 0, false)]);
+        match client.recv() {
+            ServerMessage::Daily(_) => (),
+            message => panic!("Expected DAILY, got {:?}", message),
+        }
+        daily = client.expect_words();
+    }
+    joiner.send(typed(2, false));
+    leader.send(ClientMessage::Chat("daily".to_owned()));
+    let chat = ServerMessage::Chat {
+        client_id: 1,
+        name: "P1".to_owned(),
+        message: "daily".to_owned(),
+    };
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect(std::slice::from_ref(&chat));
+    }
+
+    leader.start_race(2);
+    joiner.expect_race_start(2);
+    joiner.send(typed(daily.len(), false));
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect(&[ServerMessage::Finished(2), accurate(2)]);
+    }
+
+    server.shut_down();
+}
+
+#[test]
+fn leaders_are_succeeded_and_joiners_catch_up_on_chat() {
+    let server = TestServer::start_with(|config| {
+        config.lobby.succession = SuccessionPolicy::Oldest;
+    });
+    let mut leader = server.connect(1);
+    let mut joiner = server.connect(2);
+    let mut third = server.connect(3);
+    let mut late = server.connect(4);
+
+    let (code, words) = leader.create_lobby();
+    joiner.send(ClientMessage::Join(code));
+    joiner.expect(&[ServerMessage::Joined(code)]);
+    assert_eq!(joiner.expect_words(), words);
+    for client in &mut [&mut leader, &mut joiner] {
+        client.expect(&[state(1, 0, false), state(2, 0, false)]);
+    }
+    third.send(ClientMessage::Join(code));
+    third.expect(&[ServerMessage::Joined(code)]);
+    assert_eq!(third.expect_words(), words);
+    let everyone = [state(1, 0, false), state(2, 0, false), state(3, 0, false)];
+    for client in &mut [&mut leader, &mut joiner, &mut third] {
+        client.expect(&everyone);
+    }
+
+    // Only the leader can hand over the lobby, so the chat is the next thing anyone hears.
+    joiner.send(ClientMessage::Promote(3));
+    leader.send(ClientMessage::Chat("hi".to_owned()));
+    let hi = ServerMessage::Chat {
+        client_id: 1,
+        name: "P1".to_owned(),
+        message: "hi".to_owned(),
+    };
+    for client in &mut [&mut leader, &mut joiner, &mut third] {
+        client.expect(std::slice::from_ref(&hi));
+    }
+
+    leader.send(ClientMessage::Promote(2));
+    for client in &mut [&mut leader, &mut joiner, &mut third] {
+        client.expect(&[ServerMessage::NewLeader(2)]);
+    }
+
+    // The leader leaving hands the lobby to whoever has been in it longest.
+    drop(joiner);
+    for client in &mut [&mut leader, &mut third] {
+        client.expect(&[
+            ServerMessage::NewLeader(1),
+            ServerMessage::PlayerDisconnected(2),
+        ]);
+    }
+
+    third.send(ClientMessage::Chat("gg".to_owned()));
+    let gg = ServerMessage::Chat {
+        client_id: 3,
+        name: "P3".to_owned(),
+        message: "gg".to_owned(),
+    };
+    for client in &mut [&mut leader, &mut third] {
+        client.expect(std::slice::from_ref(&gg));
+    }
+
+    late.send(ClientMessage::Join(code));
+    late.expect(&[ServerMessage::Joined(code), hi, gg]);
+    assert_eq!(late.expect_words(), words);
+    let everyone = [state(1, 0, false), state(3, 0, false), state(4, 0, false)];
+    for client in &mut [&mut leader, &mut third, &mut late] {
+        client.expect(&everyone);
+    }
+
+    server.shut_down();
+}
+
+/// Sends `request` over the admin socket as is and returns whatever comes back.
+fn admin(socket: &Path, request: &[u8]) -> String {
+    let mut stream = UnixStream::connect(socket).unwrap();
+    stream.write_all(request).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut response = String::new();
+    // Dropped connections may be reset rather than closed.
+    let _ = stream.read_to_string(&mut response);
+    response
+}
+
+#[test]
+fn admin_commands_are_answered_over_the_socket() {
+    let socket = std::env::temp_dir().join(format!("typeracer-admin-{}.sock", std::process::id()));
+    // Left behind by a server that didn't exit cleanly.
+    drop(UnixListener::bind(&socket).unwrap());
+    let server = TestServer::start_with(|config| {
+        config.network.admin_socket = Some(socket.clone());
+    });
+    let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    assert!(admin_bind_error(&socket).contains("Another server"));
+
+    let mut player = server.connect(1);
+    assert_eq!(
+        admin(&socket, b"announce hello\n"),
+        "Announced to 1 clients\n"
+    );
+    player.expect(&[ServerMessage::Announcement("hello".to_owned())]);
+    assert!(admin(&socket, b"FROB\n").starts_with("ERROR"));
+
+    // Half a command or far too much of one gets no answer, and doesn't stop the next.
+    assert_eq!(admin(&socket, b"ANNOUNCE hello"), "");
+    assert_eq!(admin(&socket, &[b'A'; 8192]), "");
+    assert_eq!(admin(&socket, b"KICK 1\n"), "Kicked 1\n");
+    player.expect(&[ServerMessage::Kicked(
+        "Removed by the server operator".to_owned(),
+    )]);
+    player.expect_closed();
+
+    server.shut_down();
+    assert!(!socket.exists());
+
+    // A typo in the path doesn't cost anyone their file.
+    let file = std::env::temp_dir().join(format!("typeracer-admin-{}.txt", std::process::id()));
+    std::fs::write(&file, "precious").unwrap();
+    assert!(admin_bind_error(&file).contains("isn't a socket"));
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "precious");
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn silent_clients_time_out_and_latency_is_shared() {
+    let server = TestServer::start_with(|config| {
+        config.network.ping_interval = 1;
+        config.network.ping_timeout = 1;
+    });
+    let mut leader = server.connect(1);
+    let mut silent = server.connect(2);
+    let (code, _) = leader.create_lobby();
+    silent.send(ClientMessage::Join(code));
+    silent.expect(&[ServerMessage::Joined(code)]);
+    silent.expect_words();
+    for client in &mut [&mut leader, &mut silent] {
+        client.expect(&[state(1, 0, false), state(2, 0, false)]);
+    }
+
+    // The leader answers every PING and hears about the other player's timeout.
+    let mut latencies = 0;
+    loop {
+        match leader.recv() {
+            ServerMessage::Ping(nonce) => leader.send(ClientMessage::Pong(nonce)),
+            ServerMessage::Latency { client_id: 1, .. } => latencies += 1,
+            ServerMessage::PlayerDisconnected(2) => break,
+            message => panic!("Unexpected {:?}", message),
+        }
+    }
+    assert!(latencies > 0);
+
+    // The other one never answers, but still sees the leader's latency before it's dropped.
+    let mut line = String::new();
+    let mut received = vec![];
+    while silent.reader.read_line(&mut line).unwrap() > 0 {
+        received.push(ServerMessage::decode(line.as_bytes()).unwrap());
+        line.clear();
+    }
+    // The PINGs go out at about the same time, so the leader's LATENCY may beat this one's.
+    let (pings, latencies): (Vec<_>, Vec<_>) = received
+        .iter()
+        .partition(|message| matches!(message, ServerMessage::Ping(_)));
+    assert_eq!(pings, [&ServerMessage::Ping(0)]);
+    assert!(!latencies.is_empty());
+    assert!(latencies
+        .iter()
+        .all(|message| matches!(message, ServerMessage::Latency { client_id: 1, .. })));
+
+    server.shut_down();
+}